@@ -1,34 +1,165 @@
 use std::cmp;
+use std::collections::{BTreeMap, HashMap};
 use std::mem;
 
-use serde::{Deserialize, Serialize};
+use serde::de::{self, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
 
 type WorkCount = u8;
 type Value = u32;
 type Cost = u64;
+type Level = u32;
+type EnchantmentSetId = u32;
 
 const PIECE_TYPE_BOOK: bool = false;
 const PIECE_TYPE_ITEM: bool = true;
-const SATURATED_WORK_COUNT: WorkCount = 64;
+/// Java stores the repair cost as a 32-bit int and stops doubling it there,
+/// which the vanilla penalty reaches after 31 operations.
+const MAX_REPAIR_COST: Cost = 0x7FFF_FFFF;
+const SATURATED_WORK_COUNT: u64 = 31;
 const MAX_DP_WORK_STATES: usize = 1 << WorkCount::BITS;
 const MAX_XP_LOOKUP_ENTRIES: usize = 1_000_000;
+const DEFAULT_MAX_STEP_COST: Cost = 39;
+/// Java caps the cost of a rename on its own instead of rejecting it as too
+/// expensive.
+const MAX_RENAME_ONLY_COST: Cost = 39;
+/// Every anvil use has a 12% chance to wear the anvil down a stage; the third
+/// stage breaks it, losing the 3 iron blocks and 4 iron ingots it was made of.
+const ANVIL_WEAR_PERCENT: usize = 12;
+const ANVIL_WEAR_STAGES: usize = 3;
+const ANVIL_IRON_BLOCKS: usize = 3;
+const ANVIL_IRON_INGOTS: usize = 4;
+/// Largest weight the objective puts on anvil wear, tome material or the
+/// final penalty, so that every plan's cost still counts.
+const MAX_OBJECTIVE_WEIGHT: Cost = 0x7FFF_FFFF;
+/// Every bisection round reruns the solver. This many rounds settle any limit
+/// below 2^16 exactly, which covers every work count and any step a player
+/// can afford.
+const MAX_BISECTION_ROUNDS: u32 = 16;
+
+/// An enchantment whose identity is known, so that equal levels can merge into
+/// the next one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct EnchantmentLevel {
+    id: usize,
+    level: Level,
+    book_multiplier: Value,
+    item_multiplier: Value,
+    max_level: Level,
+    /// Bit set of the conflict groups this enchantment belongs to. Two
+    /// enchantments sharing a group are incompatible.
+    conflict_groups: u64,
+    /// Whether the item can hold it. Books carry it regardless, but an item
+    /// skips it for free.
+    fits_item: bool,
+}
+
+/// Wear of an item that should come out of the plan repaired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct Durability {
+    damage: Value,
+    max: Value,
+    /// Units of the item's repair material are at hand.
+    #[serde(default = "default_material")]
+    material: bool,
+}
 
 #[derive(Default, Debug, Clone)]
 struct Piece {
     name_indices: Vec<usize>,
     is_item: bool,
+    /// Value of the enchantments without a known identity when sacrificed as a
+    /// book. These never interact, so their value is simply additive.
     value: Value,
+    /// The same value using the multipliers for an item sacrifice.
+    item_value: Value,
+    /// Identified enchantments, sorted by id.
+    enchantments: Vec<EnchantmentLevel>,
     work_count: WorkCount,
+    /// The final item should carry a new name.
+    rename: bool,
+    /// Only set on items, and then on all of them.
+    durability: Option<Durability>,
+    /// Bit set of the `config.cost_overrides` names among the input pieces
+    /// this piece was made of.
+    override_names: u64,
+}
+
+impl EnchantmentLevel {
+    const fn multiplier(&self, sacrifice_is_item: bool) -> Value {
+        if sacrifice_is_item {
+            self.item_multiplier
+        } else {
+            self.book_multiplier
+        }
+    }
+}
+
+impl Piece {
+    /// Value of the anonymous enchantments in the column this piece is
+    /// costed with when sacrificed.
+    const fn sacrifice_value(&self) -> Value {
+        if self.is_item {
+            self.item_value
+        } else {
+            self.value
+        }
+    }
+
+    fn total_value(&self) -> Value {
+        self.enchantments
+            .iter()
+            .map(|enchantment| enchantment.level * enchantment.multiplier(self.is_item))
+            .sum::<Value>()
+            + self.sacrifice_value()
+    }
 }
 
+/// One anvil operation. Without a sacrifice, the step only renames the
+/// target.
 #[derive(Default, Debug, Clone)]
 struct TraceRecord {
     left: Piece,
-    right: Piece,
+    right: Option<Piece>,
+    rename: bool,
+    /// Repairs the target with units of its material instead.
+    material_repair: bool,
+    /// Moves the target's enchantments onto a book with a tome instead.
+    tome: bool,
+}
+
+/// Everything about a state's result that later steps depend on, besides its
+/// cost.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct FrontierKey {
+    work_count: WorkCount,
+    enchantments: EnchantmentSetId,
+    renamed: bool,
+    damage: Value,
 }
 
+/// A step on its own, such as renaming, refers back to its own state, while
+/// leaves refer to state 0.
 #[derive(Default, Debug, Clone, Copy)]
 struct DpEntry {
+    key: FrontierKey,
+    cost: Cost,
+    left_state: usize,
+    left: FrontierKey,
+    right: FrontierKey,
+    /// Whether the step producing this entry renames the target.
+    renames: bool,
+    /// Whether the step producing this entry is a material repair.
+    repairs: bool,
+    /// Whether this entry was reset with a tome.
+    tomes: bool,
+}
+
+/// An entry of a state without a frontier key, where entries differ only in
+/// work count. The fast path merges these across most states, so they stay
+/// as small as a work count and its split.
+#[derive(Default, Debug, Clone, Copy)]
+struct WorkEntry {
     work_count: WorkCount,
     cost: Cost,
     left_state: usize,
@@ -36,24 +167,85 @@ struct DpEntry {
     right_work_count: WorkCount,
 }
 
+const fn work_key(work_count: WorkCount) -> FrontierKey {
+    FrontierKey {
+        work_count,
+        enchantments: EnchantmentSets::EMPTY,
+        renamed: false,
+        damage: 0,
+    }
+}
+
+impl From<WorkEntry> for DpEntry {
+    fn from(entry: WorkEntry) -> Self {
+        Self {
+            key: work_key(entry.work_count),
+            cost: entry.cost,
+            left_state: entry.left_state,
+            left: work_key(entry.left_work_count),
+            right: work_key(entry.right_work_count),
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SolveError {
+    NoInputs,
+    Infeasible,
+}
+
 #[derive(Default, Debug, Clone, Copy)]
 struct DpRange {
     start: usize,
-    len: usize,
+    /// Narrower than `start` so the flag fits in the same 16 bytes.
+    len: u32,
+    /// Whether the range is in the keyed arena rather than the work arena.
+    keyed: bool,
+}
+
+impl DpRange {
+    #[inline]
+    const fn indices(self) -> std::ops::Range<usize> {
+        self.start..self.start + self.len as usize
+    }
 }
 
 #[derive(Debug)]
 struct PieceGroup {
     value: Value,
+    item_value: Value,
+    enchantments: Vec<EnchantmentLevel>,
     work_count: WorkCount,
     is_item: bool,
+    durability: Option<Durability>,
+    override_names: u64,
     members: Vec<Piece>,
     stride: usize,
 }
 
+/// The entries of a state from whichever arena holds them.
+#[inline]
+fn dp_entries<'a>(
+    arena: &'a [DpEntry],
+    work_arena: &'a [WorkEntry],
+    range: DpRange,
+) -> impl Iterator<Item = DpEntry> + 'a {
+    let (entries, work_entries) = if range.keyed {
+        (&arena[range.indices()], &[][..])
+    } else {
+        (&[][..], work_entries(work_arena, range))
+    };
+    entries
+        .iter()
+        .copied()
+        .chain(work_entries.iter().map(|&entry| DpEntry::from(entry)))
+}
+
 #[inline]
-fn dp_entries(arena: &[DpEntry], range: DpRange) -> &[DpEntry] {
-    &arena[range.start..range.start + range.len]
+fn work_entries(work_arena: &[WorkEntry], range: DpRange) -> &[WorkEntry] {
+    debug_assert!(!range.keyed);
+    &work_arena[range.indices()]
 }
 
 #[derive(Debug)]
@@ -73,96 +265,423 @@ impl XpLookup {
     }
 }
 
+/// Huge penalties make for levels whose XP no longer fits a `Cost`, so the
+/// vanilla curves are evaluated on wider integers and saturate, which they
+/// already do well before 2^32 levels.
 #[inline]
-const fn calc_xp(level: Cost) -> Cost {
-    if level < 16 {
-        level.pow(2) + 6 * level
-    } else if level < 32 {
-        (5 * level.pow(2) + 720 - 81 * level) / 2
-    } else {
-        (9 * level.pow(2) + 4_440 - 325 * level) / 2
-    }
+fn saturating_xp(level: Cost, xp: impl Fn(u128) -> u128) -> Cost {
+    Cost::try_from(xp(u128::from(cmp::min(level, 1 << 32)))).unwrap_or(Cost::MAX)
 }
 
-const fn calc_level(xp: Cost) -> Cost {
-    let mut test_xp = 0;
-    let mut level = 0;
-    while test_xp < xp {
-        level += 1;
-        test_xp = calc_xp(level);
-    }
-    level
+#[inline]
+fn calc_xp(level: Cost) -> Cost {
+    saturating_xp(level, |level| {
+        if level < 16 {
+            level.pow(2) + 6 * level
+        } else if level < 32 {
+            (5 * level.pow(2) + 720 - 81 * level) / 2
+        } else {
+            (9 * level.pow(2) + 4_440 - 325 * level) / 2
+        }
+    })
+}
+
+/// Total XP of a level before 1.8, when levels up to 15 took 17 points each.
+#[inline]
+fn calc_legacy_xp(level: Cost) -> Cost {
+    saturating_xp(level, |level| {
+        if level < 16 {
+            17 * level
+        } else if level < 31 {
+            (3 * level.pow(2) + 720 - 59 * level) / 2
+        } else {
+            (7 * level.pow(2) + 4_440 - 303 * level) / 2
+        }
+    })
 }
 
-const fn displayed_total_cost(
+fn displayed_total_cost(
+    xp_curve: &XpCurve,
     optimize_per_step: bool,
     total_level_cost: Cost,
     separately_funded_xp_cost: Cost,
 ) -> (Cost, Cost) {
     if optimize_per_step {
         (
-            calc_level(separately_funded_xp_cost),
+            xp_curve.level(separately_funded_xp_cost),
             separately_funded_xp_cost,
         )
     } else {
-        (total_level_cost, calc_xp(total_level_cost))
+        (total_level_cost, xp_curve.xp(total_level_cost))
     }
 }
 
-const fn calc_penalty(work_count: WorkCount) -> Cost {
+const fn calc_penalty(work_count: u64) -> Cost {
     if work_count >= SATURATED_WORK_COUNT {
-        Cost::MAX
+        MAX_REPAIR_COST
     } else {
         (1_u64 << work_count) - 1
     }
 }
 
-const fn calc_work_count(penalty: WorkCount) -> WorkCount {
-    let mut test_penalty = 0_u64;
-    let mut work_count = 0;
-    while test_penalty < penalty as Cost {
-        work_count += 1;
-        test_penalty = calc_penalty(work_count);
+/// Applies the sacrifice's enchantments to the target's. Equal levels bump by
+/// one up to the maximum level, otherwise the higher level wins. Each
+/// sacrifice enchantment costs its resulting level times its multiplier.
+/// Enchantments incompatible with the target are dropped instead, at a cost
+/// of one level per target enchantment they conflict with.
+///
+/// Bedrock only charges for the levels the sacrifice adds to the target and
+/// drops incompatible enchantments for free.
+fn merge_enchantments(
+    edition: Edition,
+    target: &[EnchantmentLevel],
+    sacrifice: &[EnchantmentLevel],
+    target_is_item: bool,
+    sacrifice_is_item: bool,
+) -> (Vec<EnchantmentLevel>, Cost) {
+    let mut merged = Vec::with_capacity(target.len() + sacrifice.len());
+    let mut cost: Cost = 0;
+    let target_set = target;
+    let mut target = target.iter().peekable();
+    for enchantment in sacrifice {
+        while let Some(existing) = target.next_if(|existing| existing.id < enchantment.id) {
+            merged.push(*existing);
+        }
+        let conflicts = target_set
+            .iter()
+            .filter(|existing| {
+                existing.id != enchantment.id
+                    && existing.conflict_groups & enchantment.conflict_groups != 0
+            })
+            .count();
+        if conflicts != 0 {
+            if edition == Edition::Java {
+                cost += conflicts as Cost;
+            }
+            continue;
+        }
+        if target_is_item && !enchantment.fits_item {
+            continue;
+        }
+        let existing_level = target
+            .next_if(|existing| existing.id == enchantment.id)
+            .map_or(0, |existing| existing.level);
+        let level = if existing_level == enchantment.level {
+            cmp::min(existing_level + 1, enchantment.max_level)
+        } else {
+            cmp::max(existing_level, enchantment.level)
+        };
+        let charged_levels = match edition {
+            Edition::Java => level,
+            Edition::Bedrock => level - existing_level,
+        };
+        cost += Cost::from(charged_levels) * Cost::from(enchantment.multiplier(sacrifice_is_item));
+        merged.push(EnchantmentLevel {
+            level,
+            ..*enchantment
+        });
+    }
+    merged.extend(target.copied());
+    (merged, cost)
+}
+
+/// Interns the enchantment sets reachable while merging, so DP entries can
+/// refer to them by id and each distinct merge is only computed once.
+#[derive(Debug, Default)]
+struct EnchantmentSets {
+    edition: Edition,
+    sets: Vec<Box<[EnchantmentLevel]>>,
+    ids: HashMap<Box<[EnchantmentLevel]>, EnchantmentSetId>,
+    merges: HashMap<(EnchantmentSetId, EnchantmentSetId, bool, bool), (EnchantmentSetId, Cost)>,
+}
+
+impl EnchantmentSets {
+    const EMPTY: EnchantmentSetId = 0;
+
+    fn new(edition: Edition) -> Self {
+        let mut sets = Self {
+            edition,
+            ..Self::default()
+        };
+        let empty = sets.intern(&[]);
+        debug_assert_eq!(empty, Self::EMPTY);
+        sets
+    }
+
+    fn intern(&mut self, set: &[EnchantmentLevel]) -> EnchantmentSetId {
+        if let Some(&id) = self.ids.get(set) {
+            return id;
+        }
+        let id = EnchantmentSetId::try_from(self.sets.len())
+            .expect("enchantment set count exceeds supported size");
+        self.sets.push(set.into());
+        self.ids.insert(set.into(), id);
+        id
+    }
+
+    fn merge(
+        &mut self,
+        target: EnchantmentSetId,
+        sacrifice: EnchantmentSetId,
+        target_is_item: bool,
+        sacrifice_is_item: bool,
+    ) -> (EnchantmentSetId, Cost) {
+        let key = (target, sacrifice, target_is_item, sacrifice_is_item);
+        if let Some(&merged) = self.merges.get(&key) {
+            return merged;
+        }
+        let (set, cost) = merge_enchantments(
+            self.edition,
+            &self.sets[target as usize],
+            &self.sets[sacrifice as usize],
+            target_is_item,
+            sacrifice_is_item,
+        );
+        let merged = (self.intern(&set), cost);
+        self.merges.insert(key, merged);
+        merged
+    }
+}
+
+fn objective_cost(config: &Config, level_cost: Cost) -> Cost {
+    if config.optimize_per_step {
+        config.xp_curve.xp(level_cost)
+    } else {
+        level_cost
+    }
+}
+
+/// What the solver charges for a step: its objective cost plus the price put
+/// on wearing the anvil.
+fn step_objective_cost(config: &Config, level_cost: Cost) -> Cost {
+    objective_cost(config, level_cost).saturating_add(config.anvil_wear_cost)
+}
+
+/// Names used by `config.cost_overrides`. A name's position is its bit in
+/// `Piece::override_names`.
+fn override_names(config: &Config) -> Vec<&str> {
+    let mut names = Vec::new();
+    for cost_override in &config.cost_overrides {
+        let target = cost_override.target.as_deref();
+        for name in target.into_iter().chain([cost_override.sacrifice.as_str()]) {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    names
+}
+
+/// Resolves `config.cost_overrides` to their target bit (0 for any target),
+/// sacrifice bit and level cost, so steps can match them on bits alone.
+fn resolve_cost_overrides(config: &Config) -> Vec<(u64, u64, Cost)> {
+    let names = override_names(config);
+    let bit = |name: &str| {
+        names
+            .iter()
+            .position(|&other| other == name)
+            .map_or(0, |index| 1 << index)
+    };
+    config
+        .cost_overrides
+        .iter()
+        .map(|cost_override| {
+            let target = cost_override.target.as_deref().map_or(0, bit);
+            (target, bit(&cost_override.sacrifice), cost_override.cost)
+        })
+        .collect()
+}
+
+/// The fixed level cost of sacrificing a single input piece, from the first
+/// matching override.
+fn override_cost(config: &Config, target_names: u64, sacrifice_names: u64) -> Option<Cost> {
+    if sacrifice_names == 0 {
+        return None;
+    }
+    config
+        .resolved_cost_overrides
+        .iter()
+        .find(|&&(target, sacrifice, _)| {
+            sacrifice_names & sacrifice != 0 && (target == 0 || target_names & target != 0)
+        })
+        .map(|&(_, _, cost)| cost)
+}
+
+/// Renaming costs a level on top of the prior work penalty and, when done on
+/// its own, does not count as prior work.
+fn rename_only_level_cost(config: &Config, work_count: WorkCount) -> Cost {
+    let level_cost = config.penalty_curve.penalty(work_count).saturating_add(1);
+    match config.edition {
+        Edition::Java => cmp::min(level_cost, MAX_RENAME_ONLY_COST),
+        Edition::Bedrock => level_cost,
+    }
+}
+
+/// Java restores the sacrifice's remaining durability plus a 12% bonus, at 2
+/// levels whenever the target was damaged.
+const fn repair_with_item(damage: Value, sacrifice_damage: Value, max: Value) -> (Value, Cost) {
+    if damage == 0 {
+        return (0, 0);
+    }
+    let restored = max.saturating_sub(sacrifice_damage) + max * 12 / 100;
+    (damage.saturating_sub(restored), 2)
+}
+
+/// Each unit of material restores a quarter of the maximum durability at a
+/// level each. Returns the units a full repair takes.
+const fn material_units(damage: Value, max: Value) -> Value {
+    let restored_per_unit = if max < 4 { 1 } else { max / 4 };
+    damage.div_ceil(restored_per_unit)
+}
+
+fn repair_with_material(config: &Config, piece: &Piece, rename: bool) -> (Piece, Cost) {
+    let durability = piece
+        .durability
+        .expect("only items with durability can be repaired");
+    let level_cost = Cost::from(material_units(durability.damage, durability.max))
+        .saturating_add(config.penalty_curve.penalty(piece.work_count))
+        .saturating_add(Cost::from(rename));
+    (
+        Piece {
+            work_count: piece.work_count.saturating_add(1),
+            durability: Some(Durability {
+                damage: 0,
+                ..durability
+            }),
+            ..piece.clone()
+        },
+        objective_cost(config, level_cost),
+    )
+}
+
+/// Moves an item's enchantments onto a book without prior work, using an
+/// Apotheosis tome. The book is not an input piece, so it has no name.
+fn extract_with_tome(config: &Config, piece: &Piece) -> (Piece, Cost) {
+    let tome = config
+        .tome
+        .as_ref()
+        .expect("only configured tomes can be used");
+    (
+        Piece {
+            value: piece.value,
+            item_value: piece.item_value,
+            enchantments: piece.enchantments.clone(),
+            ..Piece::default()
+        },
+        objective_cost(config, tome.cost),
+    )
+}
+
+/// The item a tome leaves behind, without enchantments or prior work but as
+/// worn as before, to take the tome's book back.
+fn stripped_item(piece: &Piece) -> Piece {
+    Piece {
+        name_indices: piece.name_indices.clone(),
+        is_item: true,
+        durability: piece.durability,
+        override_names: piece.override_names,
+        ..Piece::default()
     }
-    work_count
 }
 
-fn anvil(config: &Config, left: &Piece, right: &Piece) -> (Piece, Cost) {
+fn anvil(config: &Config, left: &Piece, right: &Piece, rename: bool) -> (Piece, Cost) {
     let mut name_indices = Vec::with_capacity(left.name_indices.len() + right.name_indices.len());
     name_indices.extend_from_slice(&left.name_indices);
     name_indices.extend_from_slice(&right.name_indices);
     let is_item = left.is_item || right.is_item;
+    let (enchantments, enchantment_cost) = merge_enchantments(
+        config.edition,
+        &left.enchantments,
+        &right.enchantments,
+        left.is_item,
+        right.is_item,
+    );
+    let override_names = left.override_names | right.override_names;
+    let fixed_cost = if right.name_indices.len() == 1 {
+        override_cost(config, left.override_names, right.override_names)
+    } else {
+        None
+    };
     if config.books_free && !is_item {
         return (
             Piece {
                 name_indices,
                 is_item,
                 value: left.value + right.value,
+                item_value: left.item_value + right.item_value,
+                enchantments,
                 work_count: 0,
+                rename: false,
+                durability: None,
+                override_names,
             },
-            0,
+            fixed_cost.map_or(0, |level_cost| objective_cost(config, level_cost)),
         );
     }
-
-    let level_cost = Cost::from(right.value)
-        .saturating_add(calc_penalty(left.work_count))
-        .saturating_add(calc_penalty(right.work_count));
-    let cost = if config.optimize_per_step {
-        calc_xp(level_cost)
-    } else {
-        level_cost
+    let (durability, repair_cost) = match (left.durability, right.durability) {
+        (Some(target), Some(sacrifice)) => {
+            let (damage, repair_cost) =
+                repair_with_item(target.damage, sacrifice.damage, target.max);
+            (Some(Durability { damage, ..target }), repair_cost)
+        }
+        (target, _) => (target, 0),
     };
+
+    let level_cost = fixed_cost
+        .unwrap_or_else(|| {
+            Cost::from(right.sacrifice_value())
+                .saturating_add(enchantment_cost)
+                .saturating_add(repair_cost)
+                .saturating_add(config.penalty_curve.penalty(left.work_count))
+                .saturating_add(config.penalty_curve.penalty(right.work_count))
+        })
+        .saturating_add(Cost::from(rename));
     (
         Piece {
             name_indices,
             is_item,
             value: left.value + right.value,
+            item_value: left.item_value + right.item_value,
+            enchantments,
             work_count: cmp::max(left.work_count, right.work_count).saturating_add(1),
+            rename: left.rename || right.rename,
+            durability,
+            override_names,
         },
-        cost,
+        objective_cost(config, level_cost),
     )
 }
 
+/// Whether a step takes a turn at the anvil. With `books_free`, books are
+/// combined without one.
+fn uses_anvil(config: &Config, record: &TraceRecord) -> bool {
+    !(config.books_free
+        && !record.left.is_item
+        && record.right.as_ref().is_some_and(|right| !right.is_item))
+}
+
+fn anvil_uses(config: &Config, order: &[TraceRecord]) -> usize {
+    order
+        .iter()
+        .filter(|record| uses_anvil(config, record))
+        .count()
+}
+
+/// Replays one step of a trace.
+fn perform(config: &Config, record: &TraceRecord) -> (Piece, Cost) {
+    if let Some(right) = &record.right {
+        return anvil(config, &record.left, right, record.rename);
+    }
+    if record.material_repair {
+        return repair_with_material(config, &record.left, record.rename);
+    }
+    if record.tome {
+        return extract_with_tome(config, &record.left);
+    }
+    let level_cost = rename_only_level_cost(config, record.left.work_count);
+    (record.left.clone(), objective_cost(config, level_cost))
+}
+
 fn orient_split(
     mut left: usize,
     mut right: usize,
@@ -200,15 +719,23 @@ fn group_pieces(pieces: &[Piece]) -> (Vec<PieceGroup>, usize) {
         let is_item = piece.is_item;
         if let Some(group) = groups.iter_mut().find(|group| {
             group.value == piece.value
+                && group.item_value == piece.item_value
+                && group.enchantments == piece.enchantments
                 && group.work_count == piece.work_count
                 && group.is_item == is_item
+                && group.durability == piece.durability
+                && group.override_names == piece.override_names
         }) {
             group.members.push(piece.clone());
         } else {
             groups.push(PieceGroup {
                 value: piece.value,
+                item_value: piece.item_value,
+                enchantments: piece.enchantments.clone(),
                 work_count: piece.work_count,
                 is_item,
+                durability: piece.durability,
+                override_names: piece.override_names,
                 members: vec![piece.clone()],
                 stride: 0,
             });
@@ -228,9 +755,17 @@ fn group_pieces(pieces: &[Piece]) -> (Vec<PieceGroup>, usize) {
     (groups, state_count)
 }
 
-fn build_state_metadata(groups: &[PieceGroup], state_count: usize) -> (Vec<Value>, Vec<u8>) {
+/// Returns, per state, the anonymous value in the multiplier column the
+/// state is costed with as a sacrifice, and whether it holds an item or any
+/// identified enchantment.
+fn build_state_metadata(
+    groups: &[PieceGroup],
+    state_count: usize,
+) -> (Vec<Value>, Vec<u8>, Vec<u8>) {
     let mut state_values = vec![0; state_count];
+    let mut state_item_values = vec![0; state_count];
     let mut state_has_item = vec![0; state_count];
+    let mut state_has_enchantments = vec![0; state_count];
 
     for state in 1..state_count {
         let group = groups
@@ -239,9 +774,38 @@ fn build_state_metadata(groups: &[PieceGroup], state_count: usize) -> (Vec<Value
             .expect("nonempty grouped state has no pieces");
         let remaining = state - group.stride;
         state_values[state] = state_values[remaining] + group.value;
+        state_item_values[state] = state_item_values[remaining] + group.item_value;
         state_has_item[state] = state_has_item[remaining] | u8::from(group.is_item);
+        state_has_enchantments[state] =
+            state_has_enchantments[remaining] | u8::from(!group.enchantments.is_empty());
+    }
+    for (value, (&item_value, &has_item)) in state_values
+        .iter_mut()
+        .zip(state_item_values.iter().zip(&state_has_item))
+    {
+        if has_item != 0 {
+            *value = item_value;
+        }
+    }
+    (state_values, state_has_item, state_has_enchantments)
+}
+
+/// Folds a property of the groups over every state, adding one group member
+/// at a time.
+fn fold_states<T: Copy + Default>(
+    groups: &[PieceGroup],
+    state_count: usize,
+    add: impl Fn(T, &PieceGroup) -> T,
+) -> Vec<T> {
+    let mut folded = vec![T::default(); state_count];
+    for state in 1..state_count {
+        let group = groups
+            .iter()
+            .find(|group| (state / group.stride) % (group.members.len() + 1) != 0)
+            .expect("nonempty grouped state has no pieces");
+        folded[state] = add(folded[state - group.stride], group);
     }
-    (state_values, state_has_item)
+    folded
 }
 
 fn next_substate(
@@ -262,7 +826,7 @@ fn next_substate(
     false
 }
 
-fn build_xp_lookup(max_value: Value, work_state_count: usize) -> Option<XpLookup> {
+fn build_xp_lookup(config: &Config, max_value: Value, work_state_count: usize) -> Option<XpLookup> {
     let value_count = usize::try_from(max_value).ok()?.checked_add(1)?;
     let entry_count = value_count
         .checked_mul(work_state_count)?
@@ -273,14 +837,20 @@ fn build_xp_lookup(max_value: Value, work_state_count: usize) -> Option<XpLookup
 
     let penalties = (0..work_state_count)
         .map(|work| {
-            calc_penalty(WorkCount::try_from(work).expect("work count exceeds supported size"))
+            config
+                .penalty_curve
+                .penalty(WorkCount::try_from(work).expect("work count exceeds supported size"))
         })
         .collect::<Vec<_>>();
     let mut costs = Vec::with_capacity(entry_count);
     for value in 0..value_count {
         for &left_penalty in &penalties {
             for &right_penalty in &penalties {
-                costs.push(calc_xp(value as Cost + left_penalty + right_penalty));
+                costs.push(
+                    config
+                        .xp_curve
+                        .xp(value as Cost + left_penalty + right_penalty),
+                );
             }
         }
     }
@@ -296,15 +866,40 @@ struct TraceReconstructor<'a> {
     used_members: Vec<usize>,
     dp: &'a [DpRange],
     arena: &'a [DpEntry],
+    work_arena: &'a [WorkEntry],
     trace: Vec<TraceRecord>,
 }
 
 impl TraceReconstructor<'_> {
-    fn reconstruct(&mut self, state: usize, work_count: WorkCount) -> Piece {
-        let entry = dp_entries(self.arena, self.dp[state])
-            .iter()
-            .find(|entry| entry.work_count == work_count)
+    fn reconstruct(&mut self, state: usize, key: FrontierKey) -> Piece {
+        let entry = dp_entries(self.arena, self.work_arena, self.dp[state])
+            .find(|entry| entry.key == key)
             .expect("missing grouped-DP reconstruction entry");
+        if entry.left_state == state {
+            let left = self.reconstruct(state, entry.left);
+            let stripped = entry.tomes.then(|| stripped_item(&left));
+            let record = TraceRecord {
+                left,
+                right: None,
+                rename: entry.renames,
+                material_repair: entry.repairs,
+                tome: entry.tomes,
+            };
+            let result = perform(self.config, &record).0;
+            self.trace.push(record);
+            let Some(stripped) = stripped else {
+                return result;
+            };
+            let combined = anvil(self.config, &stripped, &result, false).0;
+            self.trace.push(TraceRecord {
+                left: stripped,
+                right: Some(result),
+                rename: false,
+                material_repair: false,
+                tome: false,
+            });
+            return combined;
+        }
         if entry.left_state == 0 {
             // Group members are mechanically interchangeable. Assign their
             // concrete names only when replaying the selected merge tree.
@@ -320,22 +915,252 @@ impl TraceReconstructor<'_> {
 
         let left_state = entry.left_state;
         let right_state = state - left_state;
-        let left = self.reconstruct(left_state, entry.left_work_count);
-        let right = self.reconstruct(right_state, entry.right_work_count);
-        let combined = anvil(self.config, &left, &right).0;
-        self.trace.push(TraceRecord { left, right });
+        let left = self.reconstruct(left_state, entry.left);
+        let right = self.reconstruct(right_state, entry.right);
+        let combined = anvil(self.config, &left, &right, entry.renames).0;
+        self.trace.push(TraceRecord {
+            left,
+            right: Some(right),
+            rename: entry.renames,
+            material_repair: false,
+            tome: false,
+        });
         combined
     }
 }
 
+/// Keeps a candidate unless an equally keyed entry is at least as cheap.
+fn offer(frontier: &mut HashMap<FrontierKey, DpEntry>, candidate: DpEntry) {
+    frontier
+        .entry(candidate.key)
+        .and_modify(|entry: &mut DpEntry| {
+            if candidate.cost < entry.cost {
+                *entry = candidate;
+            }
+        })
+        .or_insert(candidate);
+}
+
+/// Keeps, for each resulting enchantment set, rename status and damage, only
+/// the entries that are cheaper than every entry with a lower work count.
+fn push_keyed_frontier(
+    arena: &mut Vec<DpEntry>,
+    frontier: &mut HashMap<FrontierKey, DpEntry>,
+) -> DpRange {
+    let mut entries = frontier.drain().map(|(_, entry)| entry).collect::<Vec<_>>();
+    let group = |entry: &DpEntry| (entry.key.enchantments, entry.key.renamed, entry.key.damage);
+    entries.sort_unstable_by_key(|entry| (group(entry), entry.key.work_count));
+    let start = arena.len();
+    let mut previous_group = None;
+    let mut cheapest_lower_work = Cost::MAX;
+    for entry in entries {
+        if previous_group != Some(group(&entry)) {
+            previous_group = Some(group(&entry));
+            cheapest_lower_work = Cost::MAX;
+        }
+        if entry.cost < cheapest_lower_work {
+            cheapest_lower_work = entry.cost;
+            arena.push(entry);
+        }
+    }
+    DpRange {
+        start,
+        len: u32::try_from(arena.len() - start).expect("frontier exceeds supported size"),
+        keyed: true,
+    }
+}
+
+/// Adds a standalone rename of every unrenamed entry of an item state. A
+/// dominated entry only yields a dominated rename, since the rename cost grows
+/// with the work count, so pruning never drops the entry a rename refers to.
+fn push_rename_only(
+    config: &Config,
+    state: usize,
+    frontier: &mut HashMap<FrontierKey, DpEntry>,
+    max_step_cost: Cost,
+) {
+    let unrenamed = frontier
+        .values()
+        .filter(|entry| !entry.key.renamed)
+        .copied()
+        .collect::<Vec<_>>();
+    for entry in unrenamed {
+        let level_cost = rename_only_level_cost(config, entry.key.work_count);
+        if level_cost > max_step_cost {
+            continue;
+        }
+        offer(
+            frontier,
+            DpEntry {
+                key: FrontierKey {
+                    renamed: true,
+                    ..entry.key
+                },
+                cost: entry
+                    .cost
+                    .saturating_add(step_objective_cost(config, level_cost)),
+                left_state: state,
+                left: entry.key,
+                right: FrontierKey::default(),
+                renames: true,
+                repairs: false,
+                tomes: false,
+            },
+        );
+    }
+}
+
+/// Adds a full material repair of every damaged entry of an item state,
+/// optionally renaming in the same step. Like renames, the cost grows with
+/// the work count, so pruning keeps the entries repairs refer to.
+fn push_material_repairs(
+    config: &Config,
+    state: usize,
+    frontier: &mut HashMap<FrontierKey, DpEntry>,
+    rename_required: bool,
+    max: Value,
+    max_step_cost: Cost,
+) {
+    let damaged = frontier
+        .values()
+        .filter(|entry| entry.key.damage != 0)
+        .copied()
+        .collect::<Vec<_>>();
+    for entry in damaged {
+        let level_cost = Cost::from(material_units(entry.key.damage, max))
+            .saturating_add(config.penalty_curve.penalty(entry.key.work_count));
+        let can_rename = rename_required && !entry.key.renamed;
+        for renames in [false, true].into_iter().take(1 + usize::from(can_rename)) {
+            let level_cost = level_cost.saturating_add(Cost::from(renames));
+            if level_cost > max_step_cost {
+                continue;
+            }
+            offer(
+                frontier,
+                DpEntry {
+                    key: FrontierKey {
+                        work_count: entry.key.work_count.saturating_add(1),
+                        enchantments: entry.key.enchantments,
+                        renamed: entry.key.renamed || renames,
+                        damage: 0,
+                    },
+                    cost: entry
+                        .cost
+                        .saturating_add(step_objective_cost(config, level_cost)),
+                    left_state: state,
+                    left: entry.key,
+                    right: FrontierKey::default(),
+                    renames,
+                    repairs: true,
+                    tomes: false,
+                },
+            );
+        }
+    }
+}
+
+/// Adds a tome reset of every enchanted entry of an item state: the tome moves
+/// the enchantments onto a book, which then goes back onto the item. The cost
+/// does not depend on the work count, so resets of equally cheap entries tie.
+/// Offering the lowest work count first keeps the reset of an entry pruning
+/// keeps: an entry is only pruned by one with a lower work count that costs no
+/// more, and whose reset then costs no more either.
+fn push_tome_resets(
+    config: &Config,
+    tome: &Tome,
+    state: usize,
+    frontier: &mut HashMap<FrontierKey, DpEntry>,
+    enchantment_sets: &mut EnchantmentSets,
+    book_value: Value,
+    max_step_cost: Cost,
+) {
+    let penalties = config.penalty_curve.penalty(0).saturating_mul(2);
+    let mut entries = frontier.values().copied().collect::<Vec<_>>();
+    entries.sort_unstable_by_key(|entry| {
+        let key = entry.key;
+        (key.work_count, key.enchantments, key.renamed, key.damage)
+    });
+    for entry in entries {
+        if entry.key.enchantments == EnchantmentSets::EMPTY && book_value == 0 {
+            continue;
+        }
+        let (enchantments, enchantment_cost) = enchantment_sets.merge(
+            EnchantmentSets::EMPTY,
+            entry.key.enchantments,
+            PIECE_TYPE_ITEM,
+            PIECE_TYPE_BOOK,
+        );
+        let level_cost = Cost::from(book_value)
+            .saturating_add(enchantment_cost)
+            .saturating_add(penalties);
+        if tome.cost > max_step_cost || level_cost > max_step_cost {
+            continue;
+        }
+        offer(
+            frontier,
+            DpEntry {
+                key: FrontierKey {
+                    work_count: 1,
+                    enchantments,
+                    renamed: false,
+                    damage: entry.key.damage,
+                },
+                cost: entry
+                    .cost
+                    .saturating_add(step_objective_cost(config, tome.cost))
+                    .saturating_add(step_objective_cost(config, level_cost))
+                    .saturating_add(tome.material_cost),
+                left_state: state,
+                left: entry.key,
+                right: FrontierKey::default(),
+                renames: false,
+                repairs: false,
+                tomes: true,
+            },
+        );
+    }
+}
+
+/// Adds the steps an item can take without a sacrifice. Renaming comes both
+/// before and after repairing, since a repair raises the rename's penalty.
+fn push_unary_steps(
+    config: &Config,
+    state: usize,
+    frontier: &mut HashMap<FrontierKey, DpEntry>,
+    rename_required: bool,
+    durability: Option<Durability>,
+    max_step_cost: Cost,
+) {
+    if rename_required {
+        push_rename_only(config, state, frontier, max_step_cost);
+    }
+    if let Some(durability) = durability.filter(|durability| durability.material) {
+        push_material_repairs(
+            config,
+            state,
+            frontier,
+            rename_required,
+            durability.max,
+            max_step_cost,
+        );
+        if rename_required {
+            push_rename_only(config, state, frontier, max_step_cost);
+        }
+    }
+}
+
+/// The objective cost of an order, and its steps.
+type Solution = (Cost, Box<[TraceRecord]>);
+
 #[allow(clippy::too_many_lines)]
-fn solve(config: &Config, pieces: &[Piece]) -> Option<(Cost, Box<[TraceRecord]>)> {
+fn solve(config: &Config, pieces: &[Piece]) -> Result<Solution, SolveError> {
     if pieces.is_empty() {
-        return None;
+        return Err(SolveError::NoInputs);
     }
 
     let (groups, state_count) = group_pieces(pieces);
-    let (state_values, state_has_item) = build_state_metadata(&groups, state_count);
+    let (state_values, state_has_item, state_has_enchantments) =
+        build_state_metadata(&groups, state_count);
     let work_state_count = pieces
         .iter()
         .map(|piece| usize::from(piece.work_count))
@@ -345,24 +1170,103 @@ fn solve(config: &Config, pieces: &[Piece]) -> Option<(Cost, Box<[TraceRecord]>)
         .min(MAX_DP_WORK_STATES);
     let xp_lookup = config
         .optimize_per_step
-        .then(|| build_xp_lookup(state_values[state_count - 1], work_state_count))
+        .then(|| {
+            let max_value = state_values.iter().copied().max().unwrap_or(0);
+            build_xp_lookup(config, max_value, work_state_count)
+        })
         .flatten();
     let penalties = std::array::from_fn::<_, MAX_DP_WORK_STATES, _>(|work| {
-        calc_penalty(WorkCount::try_from(work).expect("work count exceeds supported size"))
+        config
+            .penalty_curve
+            .penalty(WorkCount::try_from(work).expect("work count exceeds supported size"))
+    });
+    let max_step_cost = config.max_step_cost.unwrap_or(Cost::MAX);
+    let rename_required = pieces.iter().any(|piece| piece.rename);
+    // Items share their maximum durability and material, so only the damage
+    // differs between them.
+    let durability = pieces.iter().find_map(|piece| piece.durability);
+    let tracks_items = rename_required || durability.is_some() || config.tome.is_some();
+    // A tome puts the anonymous value on a book, so it is priced in the book
+    // column.
+    let state_book_values = if config.tome.is_some() {
+        fold_states(&groups, state_count, |value, group| value + group.value)
+    } else {
+        Vec::new()
+    };
+    // Overrides depend on which side a piece is on, so states holding a named
+    // piece try both orientations through the keyed path.
+    let state_override_names = fold_states(&groups, state_count, |names, group| {
+        names | group.override_names
     });
+    let single_override_names = groups
+        .iter()
+        .filter(|group| group.override_names != 0)
+        .map(|group| (group.stride, group.override_names))
+        .collect::<HashMap<_, _>>();
+
+    // States holding identified enchantments need their resulting
+    // enchantment set in the frontier key, since merge order decides which
+    // levels combine. Items that must be renamed or repaired also track
+    // whether that already happened. The rest only differ in work count.
+    let is_keyed = |state: usize| {
+        state_has_enchantments[state] != 0
+            || (tracks_items && state_has_item[state] != 0)
+            || state_override_names[state] != 0
+    };
 
+    let mut enchantment_sets = EnchantmentSets::new(config.edition);
     let mut dp = vec![DpRange::default(); state_count];
-    let mut arena = Vec::with_capacity(state_count);
+    let mut arena = Vec::new();
+    let mut work_arena = Vec::with_capacity(state_count);
+    let mut keyed_frontier = HashMap::new();
     for group in &groups {
-        let start = arena.len();
-        arena.push(DpEntry {
+        if !is_keyed(group.stride) {
+            dp[group.stride] = DpRange {
+                start: work_arena.len(),
+                len: 1,
+                keyed: false,
+            };
+            work_arena.push(WorkEntry {
+                work_count: group.work_count,
+                ..WorkEntry::default()
+            });
+            continue;
+        }
+        let key = FrontierKey {
             work_count: group.work_count,
-            cost: 0,
-            left_state: 0,
-            left_work_count: 0,
-            right_work_count: 0,
-        });
-        dp[group.stride] = DpRange { start, len: 1 };
+            enchantments: enchantment_sets.intern(&group.enchantments),
+            renamed: false,
+            damage: group.durability.map_or(0, |durability| durability.damage),
+        };
+        keyed_frontier.insert(
+            key,
+            DpEntry {
+                key,
+                ..DpEntry::default()
+            },
+        );
+        if let Some(tome) = config.tome.as_ref().filter(|_| group.is_item) {
+            push_tome_resets(
+                config,
+                tome,
+                group.stride,
+                &mut keyed_frontier,
+                &mut enchantment_sets,
+                group.value,
+                max_step_cost,
+            );
+        }
+        if group.is_item {
+            push_unary_steps(
+                config,
+                group.stride,
+                &mut keyed_frontier,
+                rename_required,
+                durability,
+                max_step_cost,
+            );
+        }
+        dp[group.stride] = push_keyed_frontier(&mut arena, &mut keyed_frontier);
     }
 
     let all_groups_unique = groups.iter().all(|group| group.members.len() == 1);
@@ -383,7 +1287,8 @@ fn solve(config: &Config, pieces: &[Piece]) -> Option<(Cost, Box<[TraceRecord]>)
             }
         }
 
-        let mut best_by_work: [Option<DpEntry>; MAX_DP_WORK_STATES] = [None; MAX_DP_WORK_STATES];
+        let is_keyed = is_keyed(state);
+        let mut best_by_work: [Option<WorkEntry>; MAX_DP_WORK_STATES] = [None; MAX_DP_WORK_STATES];
         let mut candidate_right = 0usize;
         loop {
             if all_groups_unique {
@@ -411,30 +1316,125 @@ fn solve(config: &Config, pieces: &[Piece]) -> Option<(Cost, Box<[TraceRecord]>)
                 &state_values,
                 &state_has_item,
             );
-            let left_index = left_state;
-            let right_index = right_state;
             let books_are_free = config.books_free && both_books;
+            // Books combined for free don't take a turn at the anvil.
+            let step_cost = |level_cost| {
+                if books_are_free {
+                    objective_cost(config, level_cost)
+                } else {
+                    step_objective_cost(config, level_cost)
+                }
+            };
+
+            if is_keyed {
+                // Shared enchantments cost the same either way round, but
+                // the rest only cost when on the sacrifice, so same-type
+                // splits are tried in both orientations.
+                let orientations = [(left_state, right_state), (right_state, left_state)];
+                let orientation_count =
+                    1 + usize::from(state_has_item[left_state] == state_has_item[right_state]);
+                for &(left_state, right_state) in &orientations[..orientation_count] {
+                    let target_is_item = state_has_item[left_state] != 0;
+                    let sacrifice_is_item = state_has_item[right_state] != 0;
+                    let fixed_cost = single_override_names.get(&right_state).and_then(|&names| {
+                        override_cost(config, state_override_names[left_state], names)
+                    });
+                    for left_entry in dp_entries(&arena, &work_arena, dp[left_state]) {
+                        // A name is only kept by the target, so the rename
+                        // can ride along with any step on an unrenamed item.
+                        let can_rename = rename_required
+                            && state_has_item[left_state] != 0
+                            && !left_entry.key.renamed;
+                        for right_entry in dp_entries(&arena, &work_arena, dp[right_state]) {
+                            if right_entry.key.renamed {
+                                continue;
+                            }
+                            let (enchantments, enchantment_cost) = enchantment_sets.merge(
+                                left_entry.key.enchantments,
+                                right_entry.key.enchantments,
+                                target_is_item,
+                                sacrifice_is_item,
+                            );
+                            let (damage, repair_cost) = match durability {
+                                Some(durability) if sacrifice_is_item => repair_with_item(
+                                    left_entry.key.damage,
+                                    right_entry.key.damage,
+                                    durability.max,
+                                ),
+                                _ => (left_entry.key.damage, 0),
+                            };
+                            let (work_count, level_cost) = if books_are_free {
+                                (0, 0)
+                            } else {
+                                (
+                                    cmp::max(left_entry.key.work_count, right_entry.key.work_count)
+                                        .saturating_add(1),
+                                    Cost::from(state_values[right_state])
+                                        .saturating_add(enchantment_cost)
+                                        .saturating_add(repair_cost)
+                                        .saturating_add(
+                                            penalties[usize::from(left_entry.key.work_count)],
+                                        )
+                                        .saturating_add(
+                                            penalties[usize::from(right_entry.key.work_count)],
+                                        ),
+                                )
+                            };
+                            let level_cost = fixed_cost.unwrap_or(level_cost);
+                            for renames in
+                                [false, true].into_iter().take(1 + usize::from(can_rename))
+                            {
+                                let level_cost = level_cost.saturating_add(Cost::from(renames));
+                                if level_cost > max_step_cost {
+                                    continue;
+                                }
+                                offer(
+                                    &mut keyed_frontier,
+                                    DpEntry {
+                                        key: FrontierKey {
+                                            work_count,
+                                            enchantments,
+                                            renamed: left_entry.key.renamed || renames,
+                                            damage,
+                                        },
+                                        cost: left_entry
+                                            .cost
+                                            .saturating_add(right_entry.cost)
+                                            .saturating_add(step_cost(level_cost)),
+                                        left_state,
+                                        left: left_entry.key,
+                                        right: right_entry.key,
+                                        renames,
+                                        repairs: false,
+                                        tomes: false,
+                                    },
+                                );
+                            }
+                        }
+                    }
+                }
+                continue;
+            }
 
-            for left_entry in dp_entries(&arena, dp[left_index]) {
-                for right_entry in dp_entries(&arena, dp[right_index]) {
+            // Substates of a state without a key have none either.
+            for left_entry in work_entries(&work_arena, dp[left_state]) {
+                for right_entry in work_entries(&work_arena, dp[right_state]) {
+                    let left_work = left_entry.work_count;
+                    let right_work = right_entry.work_count;
                     let (work_count, merge_cost) = if books_are_free {
                         (0, 0)
                     } else {
-                        let work_count = cmp::max(left_entry.work_count, right_entry.work_count)
-                            .saturating_add(1);
+                        let work_count = cmp::max(left_work, right_work).saturating_add(1);
                         let level_cost = Cost::from(right_value)
-                            .saturating_add(penalties[usize::from(left_entry.work_count)])
-                            .saturating_add(penalties[usize::from(right_entry.work_count)]);
+                            .saturating_add(penalties[usize::from(left_work)])
+                            .saturating_add(penalties[usize::from(right_work)]);
+                        if level_cost > max_step_cost {
+                            continue;
+                        }
                         let merge_cost = if config.optimize_per_step {
                             xp_lookup.as_ref().map_or_else(
-                                || calc_xp(level_cost),
-                                |lookup| {
-                                    lookup.get(
-                                        right_value,
-                                        left_entry.work_count,
-                                        right_entry.work_count,
-                                    )
-                                },
+                                || config.xp_curve.xp(level_cost),
+                                |lookup| lookup.get(right_value, left_work, right_work),
                             )
                         } else {
                             level_cost
@@ -447,363 +1447,4206 @@ fn solve(config: &Config, pieces: &[Piece]) -> Option<(Cost, Box<[TraceRecord]>)
                         .saturating_add(merge_cost);
                     let slot = &mut best_by_work[usize::from(work_count)];
                     if slot.is_none_or(|entry| total_cost < entry.cost) {
-                        *slot = Some(DpEntry {
+                        *slot = Some(WorkEntry {
                             work_count,
                             cost: total_cost,
                             left_state,
-                            left_work_count: left_entry.work_count,
-                            right_work_count: right_entry.work_count,
+                            left_work_count: left_work,
+                            right_work_count: right_work,
                         });
                     }
                 }
             }
         }
 
-        let start = arena.len();
+        if is_keyed {
+            if let Some(tome) = config.tome.as_ref().filter(|_| state_has_item[state] != 0) {
+                push_tome_resets(
+                    config,
+                    tome,
+                    state,
+                    &mut keyed_frontier,
+                    &mut enchantment_sets,
+                    state_book_values[state],
+                    max_step_cost,
+                );
+            }
+            if state_has_item[state] != 0 {
+                push_unary_steps(
+                    config,
+                    state,
+                    &mut keyed_frontier,
+                    rename_required,
+                    durability,
+                    max_step_cost,
+                );
+            }
+            dp[state] = push_keyed_frontier(&mut arena, &mut keyed_frontier);
+            continue;
+        }
+        // Every entry here ends on a single merge, so its wear is added once
+        // rather than to every candidate. Books combined for free don't wear
+        // the anvil.
+        let wear_cost = if config.books_free && state_has_item[state] == 0 {
+            0
+        } else {
+            config.anvil_wear_cost
+        };
+        let start = work_arena.len();
         let mut len = 0;
         let mut cheapest_lower_work = Cost::MAX;
         for entry in best_by_work.into_iter().flatten() {
             if entry.cost < cheapest_lower_work {
                 cheapest_lower_work = entry.cost;
-                arena.push(entry);
+                work_arena.push(WorkEntry {
+                    cost: entry.cost.saturating_add(wear_cost),
+                    ..entry
+                });
                 len += 1;
             }
         }
-        dp[state] = DpRange { start, len };
+        dp[state] = DpRange {
+            start,
+            len,
+            keyed: false,
+        };
     }
 
+    // With material at hand the item must come out fully repaired. Without
+    // it, merges may not restore the item fully, so damage only breaks ties
+    // between equally cheap results.
     let full_state = state_count - 1;
-    let best_entry = *dp_entries(&arena, dp[full_state])
-        .iter()
-        .min_by_key(|entry| entry.cost)?;
-    let mut reconstructor = TraceReconstructor {
-        config,
-        groups: &groups,
-        used_members: vec![0; groups.len()],
-        dp: &dp,
-        arena: &arena,
-        trace: Vec::with_capacity(pieces.len() - 1),
+    let repair_required = durability.is_some_and(|durability| durability.material);
+    let candidates = dp_entries(&arena, &work_arena, dp[full_state])
+        .filter(|entry| {
+            entry.key.renamed == rename_required
+                && (!repair_required || entry.key.damage == 0)
+                && final_work_allowed(config, entry.key.work_count)
+        })
+        .map(|entry| DpEntry {
+            cost: entry
+                .cost
+                .saturating_add(final_penalty_cost(config, entry.key.work_count)),
+            ..entry
+        });
+    let reconstruct = |key| {
+        let mut reconstructor = TraceReconstructor {
+            config,
+            groups: &groups,
+            used_members: vec![0; groups.len()],
+            dp: &dp,
+            arena: &arena,
+            work_arena: &work_arena,
+            trace: Vec::with_capacity(pieces.len() - 1),
+        };
+        reconstructor.reconstruct(full_state, key);
+        reconstructor.trace.into_boxed_slice()
     };
-    reconstructor.reconstruct(full_state, best_entry.work_count);
-    Some((best_entry.cost, reconstructor.trace.into_boxed_slice()))
+    // From a starting level, the XP an order drains depends on the levels
+    // held at each step, which no subtree can price. The results of the last
+    // state are replayed instead, and the one draining the least XP wins.
+    let (best_entry, trace) = if let Some(starting_level) = config.starting_level {
+        candidates
+            .map(|entry| (entry, reconstruct(entry.key)))
+            .min_by_key(|(entry, trace)| {
+                let (drained, _) =
+                    spend_levels(config, starting_level, &level_costs(config, trace));
+                (drained, entry.cost, entry.key.damage)
+            })
+    } else {
+        candidates
+            .min_by_key(|entry| (entry.cost, entry.key.damage))
+            .map(|entry| (entry, reconstruct(entry.key)))
+    }
+    .ok_or(SolveError::Infeasible)?;
+    Ok((best_entry.cost, trace))
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Config {
-    books_free: bool,
-    optimize_per_step: bool,
+#[allow(clippy::unnecessary_wraps)]
+const fn default_max_step_cost() -> Option<Cost> {
+    Some(DEFAULT_MAX_STEP_COST)
 }
 
-type InputPiece = (String, String, WorkCount);
+/// Which edition's anvil rules to cost merges with.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Edition {
+    #[default]
+    Java,
+    Bedrock,
+}
+
+/// A game release, ordered as `major.minor.patch`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+struct GameVersion(u32, u32, u32);
+
+/// 1.8 introduced the prior work penalty and cost formulas used here; the
+/// history on <https://minecraft.fandom.com/wiki/Anvil_mechanics> lists no
+/// change to them since, so versions differ only in their enchantments.
+const FIRST_SUPPORTED_VERSION: GameVersion = GameVersion(1, 8, 0);
+/// Bedrock added enchantments in other releases than Java until 1.16, from
+/// which both share the release numbers of the enchantments they add.
+const FIRST_SUPPORTED_BEDROCK_VERSION: GameVersion = GameVersion(1, 16, 0);
+
+impl TryFrom<String> for GameVersion {
+    type Error = String;
+
+    fn try_from(version: String) -> Result<Self, String> {
+        let invalid = || format!("game_version: invalid version \"{version}\"");
+        let mut components = version.split('.').map(str::parse::<u32>);
+        let mut next = |required| match components.next() {
+            Some(component) => component.map_err(|_| invalid()),
+            None if required => Err(invalid()),
+            None => Ok(0),
+        };
+        let parsed = Self(next(true)?, next(true)?, next(false)?);
+        if components.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(parsed)
+    }
+}
+
+impl From<GameVersion> for String {
+    fn from(version: GameVersion) -> Self {
+        let GameVersion(major, minor, patch) = version;
+        if patch == 0 {
+            format!("{major}.{minor}")
+        } else {
+            format!("{major}.{minor}.{patch}")
+        }
+    }
+}
+
+/// Vanilla Java enchantments as `(name, max level, book multiplier, item
+/// multiplier, release that added them)`. 1.21 moved the item multiplier into
+/// each enchantment's data-driven `anvil_cost`, halved (at least 1) for books,
+/// which keeps the same table.
+const JAVA_ENCHANTMENTS: &[(&str, Level, Value, Value, GameVersion)] = &[
+    ("aqua affinity", 1, 2, 4, FIRST_SUPPORTED_VERSION),
+    ("bane of arthropods", 5, 1, 2, FIRST_SUPPORTED_VERSION),
+    ("blast protection", 4, 2, 4, FIRST_SUPPORTED_VERSION),
+    ("breach", 4, 2, 4, GameVersion(1, 21, 0)),
+    ("channeling", 1, 4, 8, GameVersion(1, 13, 0)),
+    ("curse of binding", 1, 4, 8, GameVersion(1, 11, 0)),
+    ("curse of vanishing", 1, 4, 8, GameVersion(1, 11, 0)),
+    ("density", 5, 1, 2, GameVersion(1, 21, 0)),
+    ("depth strider", 3, 2, 4, FIRST_SUPPORTED_VERSION),
+    ("efficiency", 5, 1, 1, FIRST_SUPPORTED_VERSION),
+    ("feather falling", 4, 1, 2, FIRST_SUPPORTED_VERSION),
+    ("fire aspect", 2, 2, 4, FIRST_SUPPORTED_VERSION),
+    ("fire protection", 4, 1, 2, FIRST_SUPPORTED_VERSION),
+    ("flame", 1, 2, 4, FIRST_SUPPORTED_VERSION),
+    ("fortune", 3, 2, 4, FIRST_SUPPORTED_VERSION),
+    ("frost walker", 2, 2, 4, GameVersion(1, 9, 0)),
+    ("impaling", 5, 2, 4, GameVersion(1, 13, 0)),
+    ("infinity", 1, 4, 8, FIRST_SUPPORTED_VERSION),
+    ("knockback", 2, 1, 2, FIRST_SUPPORTED_VERSION),
+    ("looting", 3, 2, 4, FIRST_SUPPORTED_VERSION),
+    ("loyalty", 3, 1, 1, GameVersion(1, 13, 0)),
+    ("luck of the sea", 3, 2, 4, FIRST_SUPPORTED_VERSION),
+    ("lure", 3, 2, 4, FIRST_SUPPORTED_VERSION),
+    ("mending", 1, 2, 4, GameVersion(1, 9, 0)),
+    ("multishot", 1, 2, 4, GameVersion(1, 14, 0)),
+    ("piercing", 4, 1, 1, GameVersion(1, 14, 0)),
+    ("power", 5, 1, 1, FIRST_SUPPORTED_VERSION),
+    ("projectile protection", 4, 1, 2, FIRST_SUPPORTED_VERSION),
+    ("protection", 4, 1, 1, FIRST_SUPPORTED_VERSION),
+    ("punch", 2, 2, 4, FIRST_SUPPORTED_VERSION),
+    ("quick charge", 3, 1, 2, GameVersion(1, 14, 0)),
+    ("respiration", 3, 2, 4, FIRST_SUPPORTED_VERSION),
+    ("riptide", 3, 2, 4, GameVersion(1, 13, 0)),
+    ("sharpness", 5, 1, 1, FIRST_SUPPORTED_VERSION),
+    ("silk touch", 1, 4, 8, FIRST_SUPPORTED_VERSION),
+    ("smite", 5, 1, 2, FIRST_SUPPORTED_VERSION),
+    ("soul speed", 3, 4, 8, GameVersion(1, 16, 0)),
+    ("sweeping edge", 3, 2, 4, GameVersion(1, 11, 1)),
+    ("swift sneak", 3, 4, 8, GameVersion(1, 19, 0)),
+    ("thorns", 3, 4, 8, FIRST_SUPPORTED_VERSION),
+    ("unbreaking", 3, 1, 2, FIRST_SUPPORTED_VERSION),
+    ("wind burst", 3, 2, 4, GameVersion(1, 21, 0)),
+];
+
+/// Where Bedrock's cost table differs from `JAVA_ENCHANTMENTS`, as `(name,
+/// book and item multipliers)`, with `None` for an enchantment Bedrock lacks.
+const BEDROCK_MULTIPLIERS: &[(&str, Option<(Value, Value)>)] =
+    &[("impaling", Some((1, 2))), ("sweeping edge", None)];
+
+const ARMOR: &[&str] = &["helmet", "chestplate", "leggings", "boots"];
+const MINING_TOOLS: &[&str] = &["pickaxe", "shovel", "axe", "hoe"];
+
+/// Item categories a vanilla Java enchantment can be applied to with an
+/// anvil. `*` stands for any item with durability.
+fn java_applies_to(name: &str) -> &'static [&'static str] {
+    match name {
+        "protection"
+        | "fire protection"
+        | "blast protection"
+        | "projectile protection"
+        | "thorns" => ARMOR,
+        "aqua affinity" | "respiration" => &["helmet"],
+        "swift sneak" => &["leggings"],
+        "depth strider" | "feather falling" | "frost walker" | "soul speed" => &["boots"],
+        "curse of binding" => &["helmet", "chestplate", "leggings", "boots", "elytra"],
+        "sharpness" => &["sword", "axe"],
+        "smite" | "bane of arthropods" => &["sword", "axe", "mace"],
+        "fire aspect" => &["sword", "mace"],
+        "knockback" | "looting" | "sweeping edge" => &["sword"],
+        "density" | "breach" | "wind burst" => &["mace"],
+        "efficiency" => &["pickaxe", "shovel", "axe", "hoe", "shears"],
+        "fortune" | "silk touch" => MINING_TOOLS,
+        "flame" | "infinity" | "power" | "punch" => &["bow"],
+        "multishot" | "piercing" | "quick charge" => &["crossbow"],
+        "channeling" | "impaling" | "loyalty" | "riptide" => &["trident"],
+        "luck of the sea" | "lure" => &["fishing_rod"],
+        _ => &["*"],
+    }
+}
+
+/// Whether an enchantment restricted to `applies_to` fits `item_type`. A
+/// category matches item types named after it, such as `sword` for
+/// `diamond_sword`; an empty list fits any item.
+fn applies_to(applies_to: &[String], item_type: &str) -> bool {
+    applies_to.is_empty()
+        || applies_to.iter().any(|category| {
+            category == "*"
+                || category == item_type
+                || item_type
+                    .strip_suffix(category.as_str())
+                    .is_some_and(|prefix| prefix.ends_with('_'))
+        })
+}
+
+/// Vanilla Java conflict groups and the release from which they apply, which
+/// Bedrock shares.
+const JAVA_CONFLICTS: &[(&[&str], GameVersion)] = &[
+    (
+        &[
+            "protection",
+            "blast protection",
+            "fire protection",
+            "projectile protection",
+        ],
+        FIRST_SUPPORTED_VERSION,
+    ),
+    (
+        &["sharpness", "smite", "bane of arthropods"],
+        FIRST_SUPPORTED_VERSION,
+    ),
+    (
+        &[
+            "sharpness",
+            "smite",
+            "bane of arthropods",
+            "density",
+            "breach",
+        ],
+        GameVersion(1, 21, 0),
+    ),
+    (&["silk touch", "fortune"], FIRST_SUPPORTED_VERSION),
+    (&["depth strider", "frost walker"], GameVersion(1, 9, 0)),
+    (&["infinity", "mending"], GameVersion(1, 11, 0)),
+    (&["riptide", "loyalty"], GameVersion(1, 13, 0)),
+    (&["riptide", "channeling"], GameVersion(1, 13, 0)),
+    (&["multishot", "piercing"], GameVersion(1, 14, 0)),
+];
+
+/// Adds the enchantments, cost table and conflicts of `config.game_version`
+/// in `config.edition` to the config. Enchantments the config already lists
+/// keep their max level. The edition alone picks the cost rules, which are
+/// the same for every supported version.
+fn apply_game_version(mut config: Config) -> Result<Config, String> {
+    let Some(version) = config.game_version else {
+        return Ok(config);
+    };
+    let first_supported = match config.edition {
+        Edition::Java => FIRST_SUPPORTED_VERSION,
+        Edition::Bedrock => FIRST_SUPPORTED_BEDROCK_VERSION,
+    };
+    if version < first_supported {
+        return Err(format!(
+            "game_version: anvil rules before {} are not supported",
+            String::from(first_supported)
+        ));
+    }
+    for &(name, max_level, book_multiplier, item_multiplier, since) in JAVA_ENCHANTMENTS {
+        let bedrock_multipliers = BEDROCK_MULTIPLIERS
+            .iter()
+            .find(|&&(other, _)| other == name)
+            .filter(|_| config.edition == Edition::Bedrock)
+            .map(|&(_, multipliers)| multipliers);
+        let (book_multiplier, item_multiplier) = match bedrock_multipliers {
+            None => (book_multiplier, item_multiplier),
+            Some(Some(multipliers)) => multipliers,
+            Some(None) => continue,
+        };
+        if since <= version {
+            // The config's max level wins, and its categories extend the
+            // vanilla ones.
+            let enchantment =
+                config
+                    .enchantments
+                    .entry(String::from(name))
+                    .or_insert(EnchantmentConfig {
+                        max_level,
+                        multipliers: None,
+                        applies_to: Vec::new(),
+                    });
+            enchantment.multipliers = Some((book_multiplier, item_multiplier));
+            enchantment.applies_to.extend(
+                java_applies_to(name)
+                    .iter()
+                    .map(|&category| String::from(category)),
+            );
+        }
+    }
+    // A later group replaces the earlier one it extends.
+    let conflicts = JAVA_CONFLICTS
+        .iter()
+        .filter(|(_, since)| *since <= version)
+        .map(|(names, _)| *names)
+        .collect::<Vec<_>>();
+    for &names in &conflicts {
+        let extended = conflicts.iter().any(|other| {
+            other.len() > names.len() && names.iter().all(|name| other.contains(name))
+        });
+        if !extended {
+            config
+                .conflicts
+                .push(names.iter().map(|&name| String::from(name)).collect());
+        }
+    }
+    Ok(config)
+}
+
+/// Anvil rules of popular mods, following each mod's default configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Ruleset {
+    /// No "Too Expensive!" cap, and books combine for free through the
+    /// Enchantment Library.
+    Apotheosis,
+    /// No "Too Expensive!" cap.
+    Quark,
+    /// No "Too Expensive!" cap, and the prior work penalty grows by a level
+    /// per operation.
+    EasyAnvils,
+    /// Keeps the cap, but the prior work penalty stops growing at 15 levels.
+    AnvilRestored,
+}
+
+impl Ruleset {
+    /// Replaces the options the ruleset decides.
+    fn apply(self, config: &mut Config) {
+        let (books_free, max_step_cost, penalty_curve) = match self {
+            Self::Apotheosis => (true, None, PenaltyCurve::default()),
+            Self::Quark => (false, None, PenaltyCurve::default()),
+            Self::EasyAnvils => (false, None, PenaltyCurve::Preset(PenaltyPreset::Linear)),
+            Self::AnvilRestored => (
+                false,
+                Some(DEFAULT_MAX_STEP_COST),
+                PenaltyCurve::Table(vec![0, 1, 3, 7, 15]),
+            ),
+        };
+        config.books_free = books_free;
+        config.max_step_cost = max_step_cost;
+        config.penalty_curve = penalty_curve;
+    }
+}
+
+/// A named prior work penalty curve.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum PenaltyPreset {
+    /// 2^n - 1, as in vanilla.
+    Vanilla,
+    /// One level per prior operation.
+    Linear,
+    /// No penalty at all, as with mods that remove it.
+    None,
+}
+
+/// Prior work penalty per work count, either a preset or a table whose last
+/// entry repeats for higher work counts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+enum PenaltyCurve {
+    Preset(PenaltyPreset),
+    Table(Vec<Cost>),
+}
+
+impl Default for PenaltyCurve {
+    fn default() -> Self {
+        Self::Preset(PenaltyPreset::Vanilla)
+    }
+}
+
+impl PenaltyCurve {
+    fn penalty(&self, work_count: WorkCount) -> Cost {
+        self.penalty_after(u64::from(work_count))
+    }
+
+    /// The penalty after any number of operations, since input pieces can
+    /// carry far more of them than the solver tracks.
+    fn penalty_after(&self, work_count: u64) -> Cost {
+        match self {
+            Self::Preset(PenaltyPreset::Vanilla) => calc_penalty(work_count),
+            Self::Preset(PenaltyPreset::Linear) => cmp::min(work_count, MAX_REPAIR_COST),
+            Self::Preset(PenaltyPreset::None) => 0,
+            Self::Table(table) => usize::try_from(work_count)
+                .ok()
+                .and_then(|work_count| table.get(work_count))
+                .or_else(|| table.last())
+                .copied()
+                .unwrap_or(0),
+        }
+    }
+
+    /// Rounds a penalty up to the lowest work count that reaches it. Beyond
+    /// the curve's maximum, this is the first work count at the maximum.
+    fn work_count(&self, penalty: Cost) -> u64 {
+        let penalty = cmp::min(penalty, self.penalty_after(MAX_REPAIR_COST));
+        // The curve never decreases, so bisecting finds the first work
+        // count that reaches the penalty.
+        let (mut low, mut high) = (0, MAX_REPAIR_COST);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            if self.penalty_after(middle) >= penalty {
+                high = middle;
+            } else {
+                low = middle + 1;
+            }
+        }
+        low
+    }
+
+    /// The solver prefers lower work counts, which is only sound while the
+    /// penalty never decreases.
+    fn check(&self) -> Result<(), String> {
+        match self {
+            Self::Table(table) if table.is_empty() => Err(String::from(
+                "penalty_curve: the table needs at least one entry",
+            )),
+            Self::Table(table) if table.windows(2).any(|pair| pair[0] > pair[1]) => Err(
+                String::from("penalty_curve: penalties may not decrease as work count grows"),
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Levels up to which a custom XP curve must keep growing.
+const MAX_CHECKED_XP_LEVEL: Cost = 1_000;
+
+/// A named total-XP-per-level curve.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum XpPreset {
+    /// 1.8 and later.
+    #[serde(rename = "vanilla")]
+    Vanilla,
+    /// 1.3.1 up to 1.8.
+    #[serde(rename = "pre-1.8")]
+    Legacy,
+}
+
+/// A polynomial in the level, with coefficients from the constant term up,
+/// that gives the total XP from level `from` on.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct XpSegment {
+    from: Cost,
+    polynomial: Vec<f64>,
+}
+
+/// Total XP needed to reach each level. A table continues past its end at the
+/// cost of its last level.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+enum XpCurve {
+    Preset(XpPreset),
+    Table(Vec<Cost>),
+    Segments(Vec<XpSegment>),
+}
+
+impl Default for XpCurve {
+    fn default() -> Self {
+        Self::Preset(XpPreset::Vanilla)
+    }
+}
+
+impl XpCurve {
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn xp(&self, level: Cost) -> Cost {
+        match self {
+            Self::Preset(XpPreset::Vanilla) => calc_xp(level),
+            Self::Preset(XpPreset::Legacy) => calc_legacy_xp(level),
+            Self::Table(table) => usize::try_from(level)
+                .ok()
+                .and_then(|level| table.get(level))
+                .copied()
+                .unwrap_or_else(|| {
+                    let last = table.len() - 1;
+                    let last_level_cost = table[last] - table[last - 1];
+                    let extra_levels = level - last as Cost;
+                    table[last].saturating_add(extra_levels.saturating_mul(last_level_cost))
+                }),
+            Self::Segments(segments) => {
+                let segment = segments
+                    .iter()
+                    .rev()
+                    .find(|segment| segment.from <= level)
+                    .expect("XP curves start at level 0");
+                let level = u32::try_from(level).map_or(f64::INFINITY, f64::from);
+                let xp = segment
+                    .polynomial
+                    .iter()
+                    .rev()
+                    .fold(0.0, |xp, coefficient| xp * level + coefficient);
+                // Float to integer casts saturate, and rounding absorbs the
+                // error of decimal coefficients.
+                xp.round() as Cost
+            }
+        }
+    }
+
+    /// The lowest level holding at least `xp` points.
+    fn level(&self, xp: Cost) -> Cost {
+        // Total XP grows with every level, so doubling and then bisecting
+        // finds the first level that holds it.
+        let mut high = 1;
+        while high < Cost::MAX && self.xp(high) < xp {
+            high = high.saturating_mul(2);
+        }
+        let mut low = 0;
+        while low < high {
+            let middle = low + (high - low) / 2;
+            if self.xp(middle) >= xp {
+                high = middle;
+            } else {
+                low = middle + 1;
+            }
+        }
+        low
+    }
+
+    /// The solver prefers cheaper steps, which is only sound while every
+    /// level costs XP.
+    fn check(&self) -> Result<(), String> {
+        let grows = || String::from("xp_curve: total XP must grow with every level");
+        match self {
+            Self::Table(table) if table.len() < 2 => Err(String::from(
+                "xp_curve: the table needs at least two levels",
+            )),
+            Self::Table(table) if table.windows(2).any(|pair| pair[0] >= pair[1]) => Err(grows()),
+            Self::Preset(_) | Self::Table(_) => Ok(()),
+            Self::Segments(segments)
+                if segments.first().is_none_or(|segment| segment.from != 0)
+                    || segments.windows(2).any(|pair| pair[0].from >= pair[1].from) =>
+            {
+                Err(String::from(
+                    "xp_curve: segments must start at level 0, in increasing order",
+                ))
+            }
+            Self::Segments(_) => {
+                if (0..MAX_CHECKED_XP_LEVEL).any(|level| self.xp(level) >= self.xp(level + 1)) {
+                    Err(grows())
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+}
+
+const fn default_library_max_level() -> Level {
+    16
+}
+
+/// The Apotheosis Enchantment Library stores 2^(level - 1) points per
+/// deposited enchantment and pays them out as books of any level up to the
+/// highest one deposited.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Library {
+    /// Highest level the block hands out: 16 for the Enchantment Library,
+    /// 31 for the Ender Library.
+    #[serde(default = "default_library_max_level")]
+    max_level: Level,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(clippy::struct_excessive_bools)]
+struct Config {
+    books_free: bool,
+    /// Deposit all identified books into an Enchantment Library and plan
+    /// with what can be withdrawn instead.
+    #[serde(default)]
+    library: Option<Library>,
+    optimize_per_step: bool,
+    /// Minimize the costliest single step, the levels someone has to hold at
+    /// once, and only then the total. Short for `objectives: [max_step]`.
+    #[serde(default)]
+    minimize_max_step: bool,
+    /// What to optimize, most important first. The total breaks any ties
+    /// left.
+    #[serde(default)]
+    objectives: Vec<Objective>,
+    /// Also list every order that no other order beats on all of the total,
+    /// the costliest step and the final penalty.
+    #[serde(default)]
+    pareto_frontier: bool,
+    /// Extra cost per level of the final item's prior work penalty, in levels
+    /// or XP like the objective, for gear that will see the anvil again.
+    #[serde(default)]
+    final_penalty_weight: Cost,
+    /// Later anvil uses the final item must still have room for under
+    /// `max_step_cost`.
+    #[serde(default)]
+    reserve_operations: WorkCount,
+    /// The user's `max_step_cost`, which the reserved operations must fit
+    /// under. It stays put while the objectives lower `max_step_cost`.
+    #[serde(skip)]
+    reserve_max_step_cost: Option<Cost>,
+    /// Level the player starts with, to report the XP actually drained as the
+    /// plan's levels are spent one step after another. Among the orders the
+    /// solver keeps for the whole set of pieces, the one draining the least
+    /// XP wins, within the limits the objectives settle.
+    #[serde(default)]
+    starting_level: Option<Cost>,
+    /// Level the player never drops below from `starting_level`, earning more
+    /// first if needed.
+    #[serde(default)]
+    level_floor: Cost,
+    /// Highest work count the final item may end with. The solver sets it to
+    /// settle the final penalty objective.
+    #[serde(skip)]
+    max_final_work: Option<WorkCount>,
+    #[serde(default)]
+    edition: Edition,
+    /// Release of the edition whose enchantments, cost table and conflicts to
+    /// use, such as "1.21". Quote it, or YAML reads 1.20 as 1.2.
+    #[serde(default)]
+    game_version: Option<GameVersion>,
+    /// Mod whose anvil rules to use. It replaces `books_free`,
+    /// `max_step_cost` and `penalty_curve`.
+    #[serde(default)]
+    ruleset: Option<Ruleset>,
+    #[serde(default)]
+    penalty_curve: PenaltyCurve,
+    #[serde(default)]
+    xp_curve: XpCurve,
+    /// Highest level cost a single step may have. Survival Java rejects
+    /// 40 levels and above as "Too Expensive!"; `None` lifts the cap, as
+    /// in creative mode.
+    #[serde(default = "default_max_step_cost")]
+    max_step_cost: Option<Cost>,
+    /// Extra cost per anvil use, in levels or XP like the objective, for
+    /// trading XP against anvil wear. Free book merges don't use the anvil.
+    #[serde(default)]
+    anvil_wear_cost: Cost,
+    /// Enchantments with a known identity. Books named after one of these
+    /// merge with equal levels into the next level instead of adding up.
+    #[serde(default)]
+    enchantments: BTreeMap<String, EnchantmentConfig>,
+    /// Groups of mutually incompatible enchantments, such as the protection
+    /// types or infinity and mending.
+    #[serde(default)]
+    conflicts: Vec<Vec<String>>,
+    /// Fixed level costs for particular combinations, as some mods set them.
+    #[serde(default)]
+    cost_overrides: Vec<CostOverride>,
+    /// `cost_overrides` as `resolve_cost_overrides` gives them, filled in
+    /// before solving.
+    #[serde(skip)]
+    resolved_cost_overrides: Vec<(u64, u64, Cost)>,
+    /// Let the solver reset the item's prior work with Apotheosis tomes.
+    #[serde(default)]
+    tome: Option<Tome>,
+}
+
+/// Something an order can be optimized for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Objective {
+    /// The total cost, in levels or XP as `optimize_per_step` says.
+    Total,
+    /// The level cost of the costliest step.
+    MaxStep,
+    /// The prior work penalty the final item ends up with.
+    FinalPenalty,
+}
+
+/// An Apotheosis extraction tome. It moves the item's enchantments onto a
+/// book and leaves the item without prior work, so the book can go back on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Tome {
+    /// Levels the anvil charges to use the tome.
+    cost: Cost,
+    /// Extra cost per tome, in levels or XP like the objective, for crafting
+    /// it.
+    #[serde(default)]
+    material_cost: Cost,
+}
+
+/// A step whose level cost a mod fixes, in place of the anvil formula.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CostOverride {
+    /// Name of an input piece the target has to contain. Any target if left
+    /// out.
+    #[serde(default)]
+    target: Option<String>,
+    /// Name of the input piece sacrificed, on its own.
+    sacrifice: String,
+    /// Level cost of the step. A rename still adds a level.
+    cost: Cost,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EnchantmentConfig {
+    max_level: Level,
+    /// Book and item multipliers from the game version's cost table, used
+    /// when a piece only gives its level.
+    #[serde(skip)]
+    multipliers: Option<(Value, Value)>,
+    /// Item types or categories (such as `sword`) the enchantment can be
+    /// applied to. Empty means any item.
+    #[serde(default)]
+    applies_to: Vec<String>,
+}
+
+fn default_input_value() -> String {
+    String::from("0x0")
+}
+
+const fn default_material() -> bool {
+    true
+}
+
+/// A piece is either written as `[name, level x multiplier, penalty]` or as a
+/// map, which can also set the options that the short form leaves out.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InputPiece {
+    name: String,
+    #[serde(default = "default_input_value")]
+    value: String,
+    /// Prior work penalty, the item's repair cost, up to what Java stores.
+    #[serde(default)]
+    penalty: Cost,
+    /// Give the final item a new name. The solver picks the step that
+    /// carries the rename, or renames on its own if that is cheaper.
+    #[serde(default)]
+    rename: bool,
+    /// Damage and maximum durability of an item to repair along the way.
+    #[serde(default)]
+    durability: Option<Durability>,
+    /// Enchantments an item already carries, by name, each as `level x
+    /// multiplier`. The names come from `config.enchantments`.
+    #[serde(default)]
+    enchantments: BTreeMap<String, String>,
+    /// Base item, such as `diamond_sword`. Only items of the same type can be
+    /// combined, so once one item has a type, all items need the same one.
+    #[serde(default, rename = "type")]
+    item_type: Option<String>,
+}
+
+struct InputPieceVisitor;
+
+impl<'de> Visitor<'de> for InputPieceVisitor {
+    type Value = InputPiece;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("[name, level x multiplier, penalty] or a map")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<InputPiece, A::Error> {
+        let name = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let value = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        let penalty = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(2, &self))?;
+        if seq.next_element::<de::IgnoredAny>()?.is_some() {
+            return Err(de::Error::invalid_length(4, &self));
+        }
+        Ok(InputPiece {
+            name,
+            value,
+            penalty,
+            rename: false,
+            durability: None,
+            enchantments: BTreeMap::new(),
+            item_type: None,
+        })
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<InputPiece, A::Error> {
+        InputPiece::deserialize(de::value::MapAccessDeserializer::new(map))
+    }
+}
+
+fn deserialize_input_pieces<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<InputPiece>, D::Error> {
+    struct AnyForm(InputPiece);
+
+    impl<'de> Deserialize<'de> for AnyForm {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_any(InputPieceVisitor).map(AnyForm)
+        }
+    }
+
+    let pieces = Vec::<AnyForm>::deserialize(deserializer)?;
+    Ok(pieces.into_iter().map(|piece| piece.0).collect())
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Input {
+    #[serde(deserialize_with = "deserialize_input_pieces")]
     items: Vec<InputPiece>,
+    #[serde(deserialize_with = "deserialize_input_pieces")]
     books: Vec<InputPiece>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct ConfigSchema {
-    config: Config,
-    input: Input,
-}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConfigSchema {
+    config: Config,
+    input: Input,
+}
+
+fn get_name(names: &[String], name_indices: &[usize]) -> String {
+    name_indices
+        .iter()
+        .map(|&index| names[index].as_str())
+        .collect::<Vec<_>>()
+        .join(" + ")
+}
+
+/// Shows an XP amount. XP saturates at `Cost::MAX` once huge penalties take
+/// it past what a `Cost` counts, and no player can pay that.
+fn format_xp(xp: Cost) -> String {
+    if xp == Cost::MAX {
+        String::from("uncountable xp")
+    } else {
+        format!("{xp} xp")
+    }
+}
+
+fn expand_cost(config: &Config, cost: Cost) -> (Cost, Cost) {
+    if config.optimize_per_step {
+        (config.xp_curve.level(cost), cost)
+    } else {
+        (cost, config.xp_curve.xp(cost))
+    }
+}
+
+fn format_step(
+    config: &Config,
+    names: &[String],
+    index: usize,
+    record: &TraceRecord,
+    level_cost: Cost,
+    xp_cost: Cost,
+) -> String {
+    let format_piece = |piece: &Piece| {
+        // Only a tome's book is not made of input pieces.
+        let name = if piece.name_indices.is_empty() {
+            String::from("tome book")
+        } else {
+            get_name(names, &piece.name_indices)
+        };
+        format!(
+            "[{name}: {},{}]",
+            piece.total_value(),
+            config.penalty_curve.penalty(piece.work_count)
+        )
+    };
+    let mut operands = vec![format_piece(&record.left)];
+    operands.extend(record.right.as_ref().map(format_piece));
+    if let Some(durability) = record.left.durability.filter(|_| record.material_repair) {
+        let units = material_units(durability.damage, durability.max);
+        operands.push(format!("{units} material"));
+    }
+    if record.tome {
+        operands.push(String::from("tome"));
+    }
+    if record.rename {
+        operands.push(String::from("rename"));
+    }
+    format!(
+        "{}. {} = {} lvl ({})\n",
+        index + 1,
+        operands.join(" + "),
+        level_cost,
+        format_xp(xp_cost)
+    )
+}
+
+/// Describes the anvil damage stages and iron expected to be lost over `uses`
+/// anvil uses.
+fn describe_anvil_wear(uses: usize) -> String {
+    let hundredths = |value: usize| format!("{}.{:02}", value / 100, value % 100);
+    let stages = uses * ANVIL_WEAR_PERCENT;
+    format!(
+        "Anvil wear: {} damage stages over {uses} uses ({} iron blocks + {} iron ingots)\n",
+        hundredths(stages),
+        hundredths(stages * ANVIL_IRON_BLOCKS / ANVIL_WEAR_STAGES),
+        hundredths(stages * ANVIL_IRON_INGOTS / ANVIL_WEAR_STAGES),
+    )
+}
+
+/// Levels of the costliest step of an order.
+fn max_step_level_cost(config: &Config, order: &[TraceRecord]) -> Cost {
+    order
+        .iter()
+        .map(|record| expand_cost(config, perform(config, record).1).0)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Whether the final item may end with a work count: under the limit the
+/// solver sets for the final penalty objective, and leaving room for the
+/// reserved operations, each of whose penalty alone must stay under the cap.
+fn final_work_allowed(config: &Config, work_count: WorkCount) -> bool {
+    let reserved = config
+        .reserve_max_step_cost
+        .filter(|_| config.reserve_operations != 0);
+    config
+        .max_final_work
+        .is_none_or(|max_final_work| work_count <= max_final_work)
+        && reserved.is_none_or(|max_step_cost| {
+            let last_work_count = u64::from(work_count) + u64::from(config.reserve_operations) - 1;
+            config.penalty_curve.penalty_after(last_work_count) < max_step_cost
+        })
+}
+
+/// What the objective charges for the final item's prior work penalty.
+fn final_penalty_cost(config: &Config, work_count: WorkCount) -> Cost {
+    config
+        .final_penalty_weight
+        .saturating_mul(config.penalty_curve.penalty(work_count))
+}
+
+/// What the objective adds on top of the levels an order spends.
+fn extra_objective_cost(config: &Config, order: &[TraceRecord], final_work: WorkCount) -> Cost {
+    let tome_count = order.iter().filter(|record| record.tome).count();
+    config
+        .anvil_wear_cost
+        .saturating_mul(anvil_uses(config, order) as Cost)
+        .saturating_add(config.tome.as_ref().map_or(0, |tome| {
+            tome.material_cost.saturating_mul(tome_count as Cost)
+        }))
+        .saturating_add(final_penalty_cost(config, final_work))
+}
+
+/// Work count of the item an order ends with.
+fn final_work_count(config: &Config, pieces: &[Piece], order: &[TraceRecord]) -> WorkCount {
+    order.last().map_or(pieces[0].work_count, |record| {
+        perform(config, record).0.work_count
+    })
+}
+
+/// The objectives to optimize in order, ending with the total to break the
+/// ties the others leave.
+fn objectives(config: &Config) -> Vec<Objective> {
+    let mut objectives = if !config.objectives.is_empty() {
+        config.objectives.clone()
+    } else if config.minimize_max_step {
+        vec![Objective::MaxStep]
+    } else {
+        Vec::new()
+    };
+    if !objectives.contains(&Objective::Total) {
+        objectives.push(Objective::Total);
+    }
+    objectives
+}
+
+/// Bisects for the lowest limit under which `solve_under` still finds an
+/// order, starting from the value `best` reaches. Limits only ever rule out
+/// orders, so an order under one limit also fits every higher one. After
+/// `MAX_BISECTION_ROUNDS`, the lowest limit an order reached so far is kept,
+/// and the limit is returned as not settled.
+fn bisect_lowest_limit(
+    best: &mut Solution,
+    reached: impl Fn(&Solution) -> Cost,
+    solve_under: impl Fn(Cost) -> Option<Solution>,
+) -> (Cost, bool) {
+    let mut low = 0;
+    let mut high = reached(best);
+    for _ in 0..MAX_BISECTION_ROUNDS {
+        if low >= high {
+            break;
+        }
+        let middle = low + (high - low) / 2;
+        if let Some(solution) = solve_under(middle) {
+            high = reached(&solution);
+            *best = solution;
+        } else {
+            low = middle + 1;
+        }
+    }
+    (high, low >= high)
+}
+
+/// Settles the objectives one at a time. The total is settled by the solver
+/// itself, the others by lowering the step cost cap or the final work count
+/// for as long as an order no costlier than the settled total remains. The
+/// order kept is always the cheapest one under the limits so far, or from a
+/// starting level the one draining the least XP. Also returns the objectives
+/// whose bisection ran out of rounds before settling.
+fn solve_objectives(
+    config: &Config,
+    pieces: &[Piece],
+) -> Result<(Solution, Vec<Objective>), SolveError> {
+    let mut config = config.clone();
+    let mut best = solve(&config, pieces)?;
+    let mut max_total = Cost::MAX;
+    let mut unsettled = Vec::new();
+    for objective in objectives(&config) {
+        let settled = match objective {
+            Objective::Total => {
+                max_total = best.0;
+                true
+            }
+            Objective::MaxStep => {
+                let (cap, settled) = bisect_lowest_limit(
+                    &mut best,
+                    |(_, order)| max_step_level_cost(&config, order),
+                    |cap| {
+                        let capped = Config {
+                            max_step_cost: Some(cap),
+                            ..config.clone()
+                        };
+                        solve(&capped, pieces)
+                            .ok()
+                            .filter(|solution| solution.0 <= max_total)
+                    },
+                );
+                config.max_step_cost = Some(cap);
+                settled
+            }
+            Objective::FinalPenalty => {
+                let (work_count, settled) = bisect_lowest_limit(
+                    &mut best,
+                    |(_, order)| Cost::from(final_work_count(&config, pieces, order)),
+                    |work_count| {
+                        let limited = Config {
+                            max_final_work: Some(
+                                WorkCount::try_from(work_count).expect("work counts fit"),
+                            ),
+                            ..config.clone()
+                        };
+                        solve(&limited, pieces)
+                            .ok()
+                            .filter(|solution| solution.0 <= max_total)
+                    },
+                );
+                let work_count = WorkCount::try_from(work_count).expect("work counts fit");
+                // Higher work counts at the same penalty are just as good, and
+                // may leave cheaper orders.
+                let penalty = config.penalty_curve.penalty(work_count);
+                let max_final_work = (work_count..=WorkCount::MAX)
+                    .take_while(|&work_count| config.penalty_curve.penalty(work_count) == penalty)
+                    .last()
+                    .unwrap_or(work_count);
+                config.max_final_work = Some(max_final_work);
+                best = solve(&config, pieces)?;
+                settled
+            }
+        };
+        if !settled {
+            unsettled.push(objective);
+        }
+    }
+    Ok((best, unsettled))
+}
+
+/// Every order that no other order beats on all of the total, the costliest
+/// step and the final work count, cheapest first. For each step cost cap, the
+/// final work count is lowered below that of the cheapest order until none
+/// remains; the next cap is then just below the costliest step seen.
+fn solve_pareto_frontier(config: &Config, pieces: &[Piece]) -> Result<Vec<Solution>, SolveError> {
+    let outcome = |solution: &Solution| {
+        (
+            solution.0,
+            max_step_level_cost(config, &solution.1),
+            final_work_count(config, pieces, &solution.1),
+        )
+    };
+    let mut solutions = Vec::new();
+    let mut capped = config.clone();
+    loop {
+        let mut limited = capped.clone();
+        let mut max_step = None;
+        while let Ok(solution) = solve(&limited, pieces) {
+            let (_, step, work_count) = outcome(&solution);
+            max_step = cmp::max(max_step, Some(step));
+            solutions.push(solution);
+            let Some(lower) = work_count.checked_sub(1) else {
+                break;
+            };
+            limited.max_final_work = Some(lower);
+        }
+        match max_step.and_then(|step: Cost| step.checked_sub(1)) {
+            Some(cap) => capped.max_step_cost = Some(cap),
+            None => break,
+        }
+    }
+    if solutions.is_empty() {
+        return Err(solve(config, pieces).expect_err("an order was found before"));
+    }
+    let outcomes = solutions.iter().map(outcome).collect::<Vec<_>>();
+    let dominated = |index: usize| {
+        let (total, step, work_count) = outcomes[index];
+        outcomes.iter().enumerate().any(|(other, &outcome)| {
+            outcome != outcomes[index] && outcome.0 <= total && outcome.1 <= step && outcome.2 <= work_count
+                // Equal outcomes keep their first order.
+                || outcome == outcomes[index] && other < index
+        })
+    };
+    let mut frontier = solutions
+        .into_iter()
+        .enumerate()
+        .filter(|&(index, _)| !dominated(index))
+        .map(|(_, solution)| solution)
+        .collect::<Vec<_>>();
+    frontier.sort_by_key(|solution| outcome(solution));
+    Ok(frontier)
+}
+
+/// Lists the Pareto frontier as a table, followed by the steps of each order.
+fn describe_pareto_frontier(config: &Config, pieces: &[Piece], names: &[String]) -> String {
+    let Ok(frontier) = solve_pareto_frontier(config, pieces) else {
+        return String::new();
+    };
+    let mut table = String::from("\nPareto frontier:\n");
+    table += format!("{:<4}{:<24}{:<12}Final penalty\n", "", "Total", "Max step").as_str();
+    let mut plans = String::new();
+    for (plan, (_, order)) in frontier.iter().enumerate() {
+        plans += format!("\nPlan {}:\n", plan + 1).as_str();
+        let mut total_level_cost: Cost = 0;
+        let mut separately_funded_xp_cost: Cost = 0;
+        let mut max_level_cost = 0;
+        for (index, record) in order.iter().enumerate() {
+            let (level_cost, xp_cost) = expand_cost(config, perform(config, record).1);
+            total_level_cost = total_level_cost.saturating_add(level_cost);
+            separately_funded_xp_cost = separately_funded_xp_cost.saturating_add(xp_cost);
+            max_level_cost = cmp::max(max_level_cost, level_cost);
+            plans += format_step(config, names, index, record, level_cost, xp_cost).as_str();
+        }
+        let (levels, xp) = displayed_total_cost(
+            &config.xp_curve,
+            config.optimize_per_step,
+            total_level_cost,
+            separately_funded_xp_cost,
+        );
+        let penalty = config
+            .penalty_curve
+            .penalty(final_work_count(config, pieces, order));
+        table += format!(
+            "{:<4}{:<24}{:<12}{penalty}\n",
+            plan + 1,
+            format!("{levels} lvl ({})", format_xp(xp)),
+            format!("{max_level_cost} lvl")
+        )
+        .as_str();
+    }
+    table + plans.as_str()
+}
+
+/// Level costs of the steps of an order.
+fn level_costs(config: &Config, order: &[TraceRecord]) -> Vec<Cost> {
+    order
+        .iter()
+        .map(|record| expand_cost(config, perform(config, record).1).0)
+        .collect()
+}
+
+/// The XP drained by an order and, per step, the levels earned first and the
+/// level before and after it.
+type SpentLevels = (Cost, Vec<(Cost, Cost, Cost)>);
+
+/// Spends the level costs in turn from `starting_level`, never dropping below
+/// `level_floor`: a step the player can't afford is preceded by earning the
+/// levels missing.
+fn spend_levels(config: &Config, starting_level: Cost, level_costs: &[Cost]) -> SpentLevels {
+    let mut level = starting_level;
+    let mut drained: Cost = 0;
+    let mut steps = Vec::with_capacity(level_costs.len());
+    for &level_cost in level_costs {
+        let earned = config
+            .level_floor
+            .saturating_add(level_cost)
+            .saturating_sub(level);
+        level += earned;
+        let after = level - level_cost;
+        let before_xp = config.xp_curve.xp(level);
+        let step_drained = if before_xp == Cost::MAX {
+            Cost::MAX
+        } else {
+            before_xp - config.xp_curve.xp(after)
+        };
+        drained = drained.saturating_add(step_drained);
+        steps.push((earned, level, after));
+        level = after;
+    }
+    (drained, steps)
+}
+
+/// Names the objectives in order, marking those the bisection left unsettled.
+fn describe_objectives(config: &Config, unsettled: &[Objective]) -> String {
+    let total_objective = if config.optimize_per_step {
+        "exact levels for each step"
+    } else {
+        "all levels up front"
+    };
+    let objectives = objectives(config)
+        .iter()
+        .map(|objective| {
+            let name = match objective {
+                Objective::Total => total_objective,
+                Objective::MaxStep => "lowest max step",
+                Objective::FinalPenalty => "lowest final penalty",
+            };
+            if unsettled.contains(objective) {
+                format!("{name} (not settled after {MAX_BISECTION_ROUNDS} search rounds)")
+            } else {
+                String::from(name)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", then ");
+    format!("Optimized for: {objectives}\n")
+}
+
+/// Lists the player's level before and after each step.
+fn describe_spent_levels(steps: &[(Cost, Cost, Cost)]) -> String {
+    let mut result = String::from("\n");
+    for (index, &(earned, before, after)) in steps.iter().enumerate() {
+        result += format!("{}. level {before} -> {after}", index + 1).as_str();
+        if earned != 0 {
+            result += format!(" (earn {earned} lvl first)").as_str();
+        }
+        result += "\n";
+    }
+    result
+}
+
+fn describe_infeasible(config: &Config, pieces: &[Piece], names: &[String]) -> String {
+    // Without a cap, only huge penalties can push every order past what a
+    // cost can count.
+    let Some(max_step_cost) = config.max_step_cost else {
+        return String::from("No feasible order: every order costs more XP than can be counted.\n");
+    };
+    let mut result = if config.reserve_operations == 0 {
+        format!("No feasible order: every order has a step above {max_step_cost} lvl.\n")
+    } else {
+        format!(
+            "No feasible order: every order has a step above {max_step_cost} lvl or leaves \
+             room for fewer than {} more operations.\n",
+            config.reserve_operations
+        )
+    };
+
+    // The first over-cap step of the cheapest uncapped order is the smallest
+    // subtree that order cannot build in survival.
+    let uncapped = Config {
+        max_step_cost: None,
+        ..config.clone()
+    };
+    let Ok((_, order)) = solve(&uncapped, pieces) else {
+        return result;
+    };
+    let failing_step = order.iter().enumerate().find_map(|(index, record)| {
+        let (level_cost, xp_cost) = expand_cost(config, perform(config, record).1);
+        (level_cost > max_step_cost).then_some((index, record, level_cost, xp_cost))
+    });
+    if let Some((index, record, level_cost, xp_cost)) = failing_step {
+        result += "\nCheapest failing subtree:\n";
+        result += format_step(config, names, index, record, level_cost, xp_cost).as_str();
+    }
+    result
+}
+
+/// Parses `level x book multiplier`, optionally followed by `/ item
+/// multiplier`. Without one, item sacrifices use the book multiplier.
+fn parse_level_multiplier(
+    name: &str,
+    level_multiplier: &str,
+) -> Result<(Level, Value, Value), String> {
+    let parse = |component: &str| {
+        component
+            .trim()
+            .parse::<Value>()
+            .map_err(|_| format!("{name}: invalid level x multiplier \"{level_multiplier}\""))
+    };
+    let (level_book_multiplier, item_multiplier) = level_multiplier
+        .split_once('/')
+        .map_or((level_multiplier, None), |(left, right)| {
+            (left, Some(right))
+        });
+    let mut components = level_book_multiplier.split('x').map(parse);
+    let level = components.next().unwrap_or(Ok(0))?;
+    let book_multiplier = components.product::<Result<Value, _>>()?;
+    let item_multiplier = item_multiplier.map_or(Ok(book_multiplier), parse)?;
+    Ok((level, book_multiplier, item_multiplier))
+}
+
+/// Like `parse_level_multiplier`, but an enchantment from the game version's
+/// cost table fills in what the value leaves out: a bare level takes both
+/// multipliers from there, and the table's book multiplier its item one.
+fn parse_enchantment_value(
+    config: &Config,
+    name: &str,
+    level_multiplier: &str,
+) -> Result<(Level, Value, Value), String> {
+    let parsed = parse_level_multiplier(name, level_multiplier)?;
+    let Some((table_book_multiplier, table_item_multiplier)) = config
+        .enchantments
+        .get(name)
+        .and_then(|enchantment| enchantment.multipliers)
+        .filter(|_| !level_multiplier.contains('/'))
+    else {
+        return Ok(parsed);
+    };
+    let (level, book_multiplier, _) = parsed;
+    if !level_multiplier.contains('x') || book_multiplier == table_book_multiplier {
+        Ok((level, table_book_multiplier, table_item_multiplier))
+    } else {
+        Ok(parsed)
+    }
+}
+
+/// Returns, per enchantment id, the bit set of its conflict groups.
+fn build_conflict_groups(
+    config: &Config,
+    enchantment_ids: &HashMap<&str, usize>,
+) -> Result<Vec<u64>, String> {
+    let mut conflict_groups = vec![0_u64; enchantment_ids.len()];
+    if config.conflicts.len() > u64::BITS as usize {
+        return Err(format!(
+            "conflicts: at most {} groups are supported",
+            u64::BITS
+        ));
+    }
+    for (group, names) in config.conflicts.iter().enumerate() {
+        for name in names {
+            let &id = enchantment_ids
+                .get(name.as_str())
+                .ok_or_else(|| format!("conflicts: unknown enchantment {name}"))?;
+            conflict_groups[id] |= 1 << group;
+        }
+    }
+    Ok(conflict_groups)
+}
+
+/// Every input enchantment is wanted on the final item, but only one
+/// enchantment of a conflicting pair can survive the merges.
+fn check_conflicting_inputs(config: &Config, pieces: &[Piece]) -> Result<(), String> {
+    let enchantment_names = config.enchantments.keys().collect::<Vec<_>>();
+    let mut present = pieces
+        .iter()
+        .flat_map(|piece| &piece.enchantments)
+        .map(|enchantment| (enchantment.id, enchantment.conflict_groups))
+        .collect::<Vec<_>>();
+    present.sort_unstable();
+    present.dedup();
+    for (index, &(id, groups)) in present.iter().enumerate() {
+        if let Some(&(other_id, _)) = present[index + 1..]
+            .iter()
+            .find(|(_, other_groups)| groups & other_groups != 0)
+        {
+            return Err(format!(
+                "{} conflicts with {}, so the final item would lose one of them",
+                enchantment_names[id], enchantment_names[other_id]
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The enchantments with a known identity, checking that all pieces agree on
+/// their multipliers.
+struct EnchantmentRegistry<'a> {
+    config: &'a Config,
+    ids: HashMap<&'a str, usize>,
+    conflict_groups: Vec<u64>,
+    multipliers: HashMap<usize, (Value, Value)>,
+}
+
+impl<'a> EnchantmentRegistry<'a> {
+    fn new(config: &'a Config) -> Result<Self, String> {
+        let ids = config
+            .enchantments
+            .keys()
+            .enumerate()
+            .map(|(id, name)| (name.as_str(), id))
+            .collect::<HashMap<_, _>>();
+        let conflict_groups = build_conflict_groups(config, &ids)?;
+        Ok(Self {
+            config,
+            ids,
+            conflict_groups,
+            multipliers: HashMap::new(),
+        })
+    }
+
+    fn id(&self, name: &str) -> Option<usize> {
+        self.ids.get(name).copied()
+    }
+
+    fn identify(
+        &mut self,
+        id: usize,
+        name: &str,
+        level: Level,
+        book_multiplier: Value,
+        item_multiplier: Value,
+    ) -> Result<EnchantmentLevel, String> {
+        let max_level = self.config.enchantments[name].max_level;
+        if level > max_level {
+            return Err(format!(
+                "{name}: level {level} exceeds max level {max_level}"
+            ));
+        }
+        let multiplier = (book_multiplier, item_multiplier);
+        if *self.multipliers.entry(id).or_insert(multiplier) != multiplier {
+            return Err(format!("{name}: pieces disagree on the multipliers"));
+        }
+        Ok(EnchantmentLevel {
+            id,
+            level,
+            book_multiplier,
+            item_multiplier,
+            max_level,
+            conflict_groups: self.conflict_groups[id],
+            fits_item: true,
+        })
+    }
+}
+
+fn check_config(config: &Config) -> Result<(), String> {
+    config.penalty_curve.check()?;
+    config.xp_curve.check()?;
+    if config
+        .library
+        .as_ref()
+        .is_some_and(|library| library.max_level == 0)
+    {
+        return Err(String::from("library.max_level must be at least 1"));
+    }
+    if config.minimize_max_step && !config.objectives.is_empty() {
+        return Err(String::from(
+            "minimize_max_step: list max_step in objectives instead",
+        ));
+    }
+    if (1..config.objectives.len())
+        .any(|index| config.objectives[..index].contains(&config.objectives[index]))
+    {
+        return Err(String::from(
+            "objectives: each objective may only be listed once",
+        ));
+    }
+    if config.reserve_operations != 0 && config.max_step_cost.is_none() {
+        return Err(String::from(
+            "reserve_operations: only a max_step_cost limits later operations",
+        ));
+    }
+    match config.starting_level {
+        None if config.level_floor != 0 => {
+            return Err(String::from(
+                "level_floor: only applies from a starting_level",
+            ));
+        }
+        Some(starting_level) if starting_level < config.level_floor => {
+            return Err(String::from(
+                "starting_level: must not be below level_floor",
+            ));
+        }
+        _ => {}
+    }
+    for (name, weight) in [
+        ("anvil_wear_cost", config.anvil_wear_cost),
+        ("final_penalty_weight", config.final_penalty_weight),
+        (
+            "tome.material_cost",
+            config.tome.as_ref().map_or(0, |tome| tome.material_cost),
+        ),
+    ] {
+        if weight > MAX_OBJECTIVE_WEIGHT {
+            return Err(format!(
+                "{name}: at most {MAX_OBJECTIVE_WEIGHT} is supported"
+            ));
+        }
+    }
+    if override_names(config).len() > u64::BITS as usize {
+        return Err(format!(
+            "cost_overrides: at most {} names are supported",
+            u64::BITS
+        ));
+    }
+    Ok(())
+}
+
+/// Leaves out the books whose enchantments can't be applied to the item type,
+/// marks the enchantments an item would skip on the other books, and returns
+/// a report of both. Items must only list enchantments their type can hold.
+fn remove_wasted_books(
+    config: &Config,
+    item_type: &str,
+    pieces: Vec<Piece>,
+    names: &[String],
+) -> Result<(Vec<Piece>, String), String> {
+    let enchantment_names = config.enchantments.keys().collect::<Vec<_>>();
+    let mut kept = Vec::with_capacity(pieces.len());
+    let mut wasted = Vec::new();
+    for mut piece in pieces {
+        let name = get_name(names, &piece.name_indices);
+        let mut skipped = Vec::new();
+        for enchantment in &mut piece.enchantments {
+            let enchantment_name = enchantment_names[enchantment.id];
+            if applies_to(&config.enchantments[enchantment_name].applies_to, item_type) {
+                continue;
+            }
+            if piece.is_item {
+                return Err(format!(
+                    "{name}: {enchantment_name} can't be applied to a {item_type}"
+                ));
+            }
+            enchantment.fits_item = false;
+            skipped.push(enchantment_name);
+        }
+        if piece.value == 0 && skipped.len() == piece.enchantments.len() && !skipped.is_empty() {
+            wasted.push(name);
+            continue;
+        }
+        wasted.extend(
+            skipped
+                .into_iter()
+                .map(|enchantment_name| format!("{enchantment_name} on {name}")),
+        );
+        kept.push(piece);
+    }
+    if wasted.is_empty() {
+        return Ok((kept, String::new()));
+    }
+    Ok((
+        kept,
+        format!(
+            "Wasted books, they can't be applied to a {item_type}: {}\n\n",
+            wasted.join(", ")
+        ),
+    ))
+}
+
+/// Every item ends up in the final item, and Java only combines items of the
+/// same type, so each pair of items meets in some merge. Checking that the
+/// input agrees on a single type thus checks every merge, and the solver never
+/// has to consider mismatched pairs. An item without a type can't stand in
+/// for a typed one. Returns the type, if the items name one.
+fn check_item_types(input: &Input) -> Result<Option<&str>, String> {
+    if let Some(book) = input.books.iter().find(|book| book.item_type.is_some()) {
+        return Err(format!("{}: only items have a type", book.name));
+    }
+    let Some(shared_type) = input
+        .items
+        .iter()
+        .find_map(|item| item.item_type.as_deref())
+    else {
+        return Ok(None);
+    };
+    for item in &input.items {
+        match item.item_type.as_deref() {
+            Some(item_type) if item_type == shared_type => {}
+            Some(item_type) => {
+                return Err(format!(
+                    "{}: a {item_type} can't be combined with a {shared_type}",
+                    item.name
+                ));
+            }
+            None => {
+                return Err(format!(
+                    "{}: needs a type to be combined with a {shared_type}",
+                    item.name
+                ));
+            }
+        }
+    }
+    Ok(Some(shared_type))
+}
+
+/// Checks an input durability against the one all items share.
+fn check_durability(
+    name: &str,
+    piece_type: bool,
+    durability: Durability,
+    shared_durability: &mut Option<Durability>,
+) -> Result<(), String> {
+    if piece_type == PIECE_TYPE_BOOK {
+        return Err(format!("{name}: only items have durability"));
+    }
+    if durability.damage > durability.max {
+        return Err(format!(
+            "{name}: damage {} exceeds max durability {}",
+            durability.damage, durability.max
+        ));
+    }
+    let shared = Durability {
+        damage: 0,
+        ..durability
+    };
+    if *shared_durability.get_or_insert(shared) != shared {
+        return Err(format!(
+            "{name}: items disagree on the max durability or material"
+        ));
+    }
+    Ok(())
+}
+
+/// Maps an input penalty to the work count that reaches it. Work counts the
+/// solver can't track are rejected, since the report would show another
+/// penalty than the one entered.
+fn input_work_count(config: &Config, name: &str, penalty: Cost) -> Result<WorkCount, String> {
+    if penalty > MAX_REPAIR_COST {
+        return Err(format!(
+            "{name}: penalty {penalty} is above {MAX_REPAIR_COST}, the most Java stores"
+        ));
+    }
+    let work_count = config.penalty_curve.work_count(penalty);
+    let Ok(work_count) = WorkCount::try_from(work_count) else {
+        return Err(format!(
+            "{name}: penalty {penalty} takes {work_count} prior operations on this penalty \
+             curve, more than the solver tracks"
+        ));
+    };
+    Ok(work_count)
+}
+
+/// Work counts are tracked up to `WorkCount::MAX` and saturate there, which
+/// is only harmless once the penalty curve stops growing. Otherwise, rejects
+/// inputs where some order can take a piece further: one more per merge and
+/// per repair, unless the step cost cap stops the piece from merging first.
+fn check_work_headroom(config: &Config, pieces: &[Piece]) -> Result<(), String> {
+    let curve = &config.penalty_curve;
+    let highest = pieces
+        .iter()
+        .map(|piece| u64::from(piece.work_count))
+        .max()
+        .unwrap_or(0);
+    let steps = pieces.len().saturating_sub(1)
+        + pieces
+            .iter()
+            .filter(|piece| piece.durability.is_some())
+            .count();
+    let mut reachable = highest + u64::try_from(steps).expect("steps fit");
+    if let Some(max_step_cost) = config.max_step_cost {
+        let over_cap = curve.work_count(max_step_cost.saturating_add(1));
+        reachable = cmp::min(reachable, cmp::max(highest, over_cap));
+    }
+    if curve.penalty_after(reachable) > curve.penalty_after(u64::from(WorkCount::MAX)) {
+        return Err(format!(
+            "an order can take a piece to {reachable} prior operations, more than the solver \
+             tracks on this penalty curve"
+        ));
+    }
+    Ok(())
+}
+
+fn build_pieces(config: &Config, input: &Input) -> Result<(Vec<Piece>, Vec<String>), String> {
+    check_config(config)?;
+    let mut registry = EnchantmentRegistry::new(config)?;
+    let mut shared_durability = None;
+
+    let mut pieces = Vec::new();
+    let mut names = Vec::new();
+    let item_iter = input.items.iter().map(|item| (item, PIECE_TYPE_ITEM));
+    let book_iter = input.books.iter().map(|item| (item, PIECE_TYPE_BOOK));
+    for (i, (piece, piece_type)) in item_iter.chain(book_iter).enumerate() {
+        let InputPiece {
+            name,
+            value: level_multiplier,
+            penalty,
+            rename,
+            durability,
+            enchantments: listed_enchantments,
+            item_type: _,
+        } = piece.clone();
+        if rename && piece_type == PIECE_TYPE_BOOK {
+            return Err(format!("{name}: only items can be renamed"));
+        }
+        if let Some(durability) = durability {
+            check_durability(&name, piece_type, durability, &mut shared_durability)?;
+        }
+        let (level, book_multiplier, item_multiplier) =
+            parse_enchantment_value(config, &name, &level_multiplier)?;
+        let mut value = level * book_multiplier;
+        let mut item_value = level * item_multiplier;
+        let mut enchantments = Vec::new();
+        if let (PIECE_TYPE_BOOK, Some(id)) = (piece_type, registry.id(&name)) {
+            value = 0;
+            item_value = 0;
+            enchantments.push(registry.identify(
+                id,
+                &name,
+                level,
+                book_multiplier,
+                item_multiplier,
+            )?);
+        }
+        let mut contents = Vec::with_capacity(listed_enchantments.len());
+        for (enchantment, level_multiplier) in &listed_enchantments {
+            let id = registry.id(enchantment).ok_or_else(|| {
+                format!("{name}: {enchantment} is not listed in config.enchantments")
+            })?;
+            let (level, book_multiplier, item_multiplier) =
+                parse_enchantment_value(config, enchantment, level_multiplier)?;
+            enchantments.push(registry.identify(
+                id,
+                enchantment,
+                level,
+                book_multiplier,
+                item_multiplier,
+            )?);
+            contents.push(format!("{enchantment} {level}"));
+        }
+        enchantments.sort_unstable_by_key(|enchantment| enchantment.id);
+        if enchantments.windows(2).any(|pair| pair[0].id == pair[1].id) {
+            return Err(format!("{name}: lists its own enchantment again"));
+        }
+        let override_names = override_names(config)
+            .iter()
+            .position(|&other| other == name)
+            .map_or(0, |index| 1 << index);
+        let work_count = input_work_count(config, &name, penalty)?;
+        // Books are named after what they carry, so show what is on them.
+        if piece_type == PIECE_TYPE_BOOK && !contents.is_empty() {
+            names.push(format!("{name} ({})", contents.join(", ")));
+        } else {
+            names.push(name);
+        }
+        pieces.push(Piece {
+            name_indices: vec![i],
+            is_item: piece_type,
+            value,
+            item_value,
+            enchantments,
+            work_count,
+            rename,
+            durability,
+            override_names,
+        });
+    }
+
+    // Once any item is worn, every item takes part in the repair. Items
+    // without a durability are undamaged.
+    if let Some(shared) = shared_durability {
+        for piece in pieces.iter_mut().filter(|piece| piece.is_item) {
+            piece.durability.get_or_insert(shared);
+        }
+    }
+
+    check_conflicting_inputs(config, &pieces)?;
+    check_work_headroom(config, &pieces)?;
+    Ok((pieces, names))
+}
+
+/// Deposits every book with identified enchantments and withdraws, per
+/// enchantment, the fewest books that still merge into the best level its
+/// points pay for. Returns the remaining pieces and the library instructions.
+fn withdraw_from_library(
+    config: &Config,
+    library: &Library,
+    pieces: Vec<Piece>,
+    names: &mut Vec<String>,
+) -> (Vec<Piece>, String) {
+    let enchantment_names = config.enchantments.keys().collect::<Vec<_>>();
+    let mut deposits = BTreeMap::new();
+    let mut deposited = Vec::new();
+    let mut kept = Vec::with_capacity(pieces.len());
+    for piece in pieces {
+        let deposit = !piece.is_item
+            && !piece.enchantments.is_empty()
+            && piece.value == 0
+            && piece.item_value == 0;
+        if !deposit {
+            kept.push(piece);
+            continue;
+        }
+        for enchantment in piece.enchantments.into_iter().filter(|e| e.level != 0) {
+            let (_, points, highest) =
+                deposits
+                    .entry(enchantment.id)
+                    .or_insert((enchantment, 0, 0));
+            *points = Cost::saturating_add(*points, 2_u64.saturating_pow(enchantment.level - 1));
+            *highest = cmp::max(*highest, enchantment.level);
+            deposited.push(format!(
+                "{} {}",
+                enchantment_names[enchantment.id], enchantment.level
+            ));
+        }
+    }
+    if deposited.is_empty() {
+        return (kept, String::new());
+    }
+
+    let mut withdrawals = Vec::new();
+    let mut leftovers = Vec::new();
+    for (id, (enchantment, points, highest)) in deposits {
+        let name = enchantment_names[id];
+        let target_level = cmp::min(enchantment.max_level, points.ilog2() + 1);
+        let level = target_level.min(highest).min(library.max_level);
+        let count = 1_u64 << (target_level - level);
+        for _ in 0..count {
+            kept.push(Piece {
+                name_indices: vec![names.len()],
+                enchantments: vec![EnchantmentLevel {
+                    level,
+                    ..enchantment
+                }],
+                ..Piece::default()
+            });
+            names.push(name.clone());
+        }
+        withdrawals.push(if count == 1 {
+            format!("{name} {level}")
+        } else {
+            format!("{count} x {name} {level}")
+        });
+        let leftover = points - (count << (level - 1));
+        if leftover != 0 {
+            leftovers.push(format!("{name} {leftover}"));
+        }
+    }
+    let mut report = format!("Deposit in the library: {}\n", deposited.join(", "));
+    report += format!("Withdraw: {}\n", withdrawals.join(", ")).as_str();
+    if !leftovers.is_empty() {
+        report += format!("Points left: {}\n", leftovers.join(", ")).as_str();
+    }
+    report += "\n";
+    (kept, report)
+}
+
+/// Plans the cheapest order for the input and returns it as a report. An input
+/// without a feasible order still gets a report explaining why.
+///
+/// # Errors
+///
+/// Returns a message naming the offending setting or piece when the config or
+/// input is invalid, such as an unknown enchantment, conflicting inputs, items
+/// of different types or penalties the solver can't track.
+pub fn process(schema: ConfigSchema) -> Result<String, String> {
+    let input = schema.input;
+    let mut config = apply_game_version(schema.config)?;
+    if let Some(ruleset) = config.ruleset {
+        ruleset.apply(&mut config);
+    }
+    config.resolved_cost_overrides = resolve_cost_overrides(&config);
+    let item_type = check_item_types(&input)?;
+    let (mut pieces, mut names) = build_pieces(&config, &input)?;
+    let mut result = String::new();
+    if let Some(item_type) = item_type {
+        (pieces, result) = remove_wasted_books(&config, item_type, pieces, &names)?;
+    }
+    if let Some(library) = &config.library {
+        let (withdrawn, instructions) = withdraw_from_library(&config, library, pieces, &mut names);
+        pieces = withdrawn;
+        result += instructions.as_str();
+    }
+    config.reserve_max_step_cost = config.max_step_cost;
+
+    let ((best_cost, order), unsettled) = match solve_objectives(&config, &pieces) {
+        Ok(solution) => solution,
+        Err(SolveError::NoInputs) => {
+            return Ok(String::from("No inputs, calculation not possible.\n"));
+        }
+        Err(SolveError::Infeasible) => {
+            return Ok(result + describe_infeasible(&config, &pieces, &names).as_str());
+        }
+    };
+    let mut max_level_cost = 0;
+    let mut total_level_cost: Cost = 0;
+    let mut separately_funded_xp_cost: Cost = 0;
+    let mut final_durability = pieces.iter().find_map(|piece| piece.durability);
+    for (index, record) in order.iter().enumerate() {
+        let (piece, cost) = perform(&config, record);
+        if piece.durability.is_some() {
+            final_durability = piece.durability;
+        }
+        let (level_cost, xp_cost) = expand_cost(&config, cost);
+        total_level_cost = total_level_cost.saturating_add(level_cost);
+        separately_funded_xp_cost = separately_funded_xp_cost.saturating_add(xp_cost);
+        max_level_cost = cmp::max(max_level_cost, level_cost);
+        result += format_step(&config, &names, index, record, level_cost, xp_cost).as_str();
+    }
+    let spent = config
+        .starting_level
+        .map(|starting_level| spend_levels(&config, starting_level, &level_costs(&config, &order)));
+    if let Some((_, steps)) = &spent {
+        result += describe_spent_levels(steps).as_str();
+    }
+    let final_work = final_work_count(&config, &pieces, &order);
+    let extra_cost = extra_objective_cost(&config, &order, final_work);
+    if config.optimize_per_step {
+        debug_assert_eq!(
+            best_cost,
+            separately_funded_xp_cost.saturating_add(extra_cost)
+        );
+    } else {
+        debug_assert_eq!(best_cost, total_level_cost.saturating_add(extra_cost));
+    }
+    let (displayed_total_levels, displayed_total_xp) = displayed_total_cost(
+        &config.xp_curve,
+        config.optimize_per_step,
+        total_level_cost,
+        separately_funded_xp_cost,
+    );
+    result += "\n";
+    result += describe_objectives(&config, &unsettled).as_str();
+    result += format!(
+        "Max step cost: {max_level_cost} lvl ({})\n",
+        format_xp(config.xp_curve.xp(max_level_cost))
+    )
+    .as_str();
+    result += format!(
+        "Total cost: {displayed_total_levels} lvl ({})\n",
+        format_xp(displayed_total_xp)
+    )
+    .as_str();
+    result += format!(
+        "Final penalty: {}\n",
+        config.penalty_curve.penalty(final_work)
+    )
+    .as_str();
+    if let Some((drained, _)) = spent {
+        result += format!("XP drained: {}\n", format_xp(drained)).as_str();
+    }
+    if let Some(durability) = final_durability {
+        result += format!(
+            "Final durability: {}/{}\n",
+            durability.max - durability.damage,
+            durability.max
+        )
+        .as_str();
+    }
+    result += describe_anvil_wear(anvil_uses(&config, &order)).as_str();
+    if config.pareto_frontier {
+        result += describe_pareto_frontier(&config, &pieces, &names).as_str();
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn config(books_free: bool, optimize_per_step: bool) -> Config {
+        Config {
+            books_free,
+            optimize_per_step,
+            minimize_max_step: false,
+            objectives: Vec::new(),
+            pareto_frontier: false,
+            final_penalty_weight: 0,
+            reserve_operations: 0,
+            reserve_max_step_cost: None,
+            starting_level: None,
+            level_floor: 0,
+            max_final_work: None,
+            library: None,
+            edition: Edition::Java,
+            game_version: None,
+            ruleset: None,
+            penalty_curve: PenaltyCurve::default(),
+            xp_curve: XpCurve::default(),
+            max_step_cost: None,
+            anvil_wear_cost: 0,
+            enchantments: BTreeMap::new(),
+            conflicts: Vec::new(),
+            cost_overrides: Vec::new(),
+            resolved_cost_overrides: Vec::new(),
+            tome: None,
+        }
+    }
+
+    fn piece(index: usize, is_item: bool, value: Value, work_count: WorkCount) -> Piece {
+        Piece {
+            name_indices: vec![index],
+            is_item,
+            value,
+            item_value: value,
+            enchantments: Vec::new(),
+            work_count,
+            rename: false,
+            durability: None,
+            override_names: 0,
+        }
+    }
+
+    fn input_piece(name: &str, value: &str, penalty: Cost) -> InputPiece {
+        InputPiece {
+            name: String::from(name),
+            value: String::from(value),
+            penalty,
+            rename: false,
+            durability: None,
+            enchantments: BTreeMap::new(),
+            item_type: None,
+        }
+    }
+
+    fn enchanted_piece(
+        index: usize,
+        is_item: bool,
+        enchantments: &[(usize, Level)],
+        work_count: WorkCount,
+    ) -> Piece {
+        Piece {
+            enchantments: enchantments
+                .iter()
+                .map(|&(id, level)| EnchantmentLevel {
+                    id,
+                    level,
+                    book_multiplier: Value::try_from(id).unwrap() + 1,
+                    item_multiplier: 2 * Value::try_from(id).unwrap() + 1,
+                    max_level: 4,
+                    // Enchantments 1 and 2 are incompatible.
+                    conflict_groups: u64::from(id != 0),
+                    fits_item: true,
+                })
+                .collect(),
+            ..piece(index, is_item, 0, work_count)
+        }
+    }
+
+    fn brute_force(config: &Config, pieces: &[Piece]) -> Cost {
+        let rename_pending = pieces.iter().any(|piece| piece.rename);
+        let pieces = pieces
+            .iter()
+            .map(|piece| Piece {
+                rename: false,
+                ..piece.clone()
+            })
+            .collect::<Vec<_>>();
+        brute_force_search(config, &pieces, rename_pending).0
+    }
+
+    /// Returns the cheapest cost and the least final damage reaching it. Here
+    /// `Piece::rename` marks the piece that already carries the new name.
+    #[allow(clippy::too_many_lines)]
+    fn brute_force_search(
+        config: &Config,
+        pieces: &[Piece],
+        rename_pending: bool,
+    ) -> (Cost, Value) {
+        let max_step_cost = config.max_step_cost.unwrap_or(Cost::MAX);
+        // With material at hand, the item has to end up fully repaired.
+        let durability = pieces[0].durability;
+        let repaired =
+            durability.is_none_or(|durability| !durability.material || durability.damage == 0);
+        let mut best = if pieces.len() == 1 && !rename_pending && repaired {
+            (0, durability.map_or(0, |durability| durability.damage))
+        } else {
+            (Cost::MAX, Value::MAX)
+        };
+        // Takes the cost of each step, whether the steps use the anvil, and
+        // what the move costs besides.
+        let mut try_steps = |next: &[Piece],
+                             rename_pending: bool,
+                             costs: &[Cost],
+                             uses_anvil: bool,
+                             extra: Cost| {
+            if costs
+                .iter()
+                .all(|&cost| expand_cost(config, cost).0 <= max_step_cost)
+            {
+                let (cost, damage) = brute_force_search(config, next, rename_pending);
+                let wear_cost = if uses_anvil {
+                    config.anvil_wear_cost
+                } else {
+                    0
+                };
+                let step_cost = costs
+                    .iter()
+                    .map(|&cost| cost.saturating_add(wear_cost))
+                    .sum::<Cost>()
+                    .saturating_add(extra);
+                best = cmp::min(best, (cost.saturating_add(step_cost), damage));
+            }
+        };
+
+        for (index, piece) in pieces.iter().enumerate().filter(|(_, piece)| piece.is_item) {
+            if let Some(tome) = config
+                .tome
+                .as_ref()
+                .filter(|_| !piece.enchantments.is_empty() || piece.value != 0)
+            {
+                let (book, extract_cost) = extract_with_tome(config, piece);
+                let (reset, apply_cost) = anvil(config, &stripped_item(piece), &book, false);
+                // Resetting an item that has just been reset changes nothing,
+                // and resetting a renamed one only throws the name away.
+                let unchanged = piece.work_count == reset.work_count;
+                if !unchanged && !piece.rename {
+                    let mut next = pieces.to_vec();
+                    next[index] = reset;
+                    try_steps(
+                        &next,
+                        rename_pending,
+                        &[extract_cost, apply_cost],
+                        true,
+                        tome.material_cost,
+                    );
+                }
+            }
+            if rename_pending {
+                let level_cost = rename_only_level_cost(config, piece.work_count);
+                let mut next = pieces.to_vec();
+                next[index].rename = true;
+                try_steps(&next, false, &[objective_cost(config, level_cost)], true, 0);
+            }
+            if piece
+                .durability
+                .is_some_and(|durability| durability.material && durability.damage != 0)
+            {
+                let mut next = pieces.to_vec();
+                for rename in [false, true] {
+                    if rename && !rename_pending {
+                        continue;
+                    }
+                    let (mut repaired, repair_cost) = repair_with_material(config, piece, rename);
+                    repaired.rename |= rename;
+                    next[index] = repaired;
+                    try_steps(&next, rename_pending && !rename, &[repair_cost], true, 0);
+                }
+            }
+        }
+        for first in 0..pieces.len() {
+            for second in first + 1..pieces.len() {
+                for (left, right) in [
+                    (&pieces[first], &pieces[second]),
+                    (&pieces[second], &pieces[first]),
+                ] {
+                    // Items can only take sacrifices; they never are one
+                    // for a book.
+                    if right.is_item && !left.is_item {
+                        continue;
+                    }
+                    // A sacrifice's name is lost.
+                    if right.rename {
+                        continue;
+                    }
+
+                    for rename in [false, true] {
+                        if rename && !(rename_pending && left.is_item) {
+                            continue;
+                        }
+                        let (mut combined, merge_cost) = anvil(config, left, right, rename);
+                        combined.rename |= rename;
+                        let mut next = Vec::with_capacity(pieces.len() - 1);
+                        next.extend(
+                            pieces
+                                .iter()
+                                .enumerate()
+                                .filter(|(index, _)| *index != first && *index != second)
+                                .map(|(_, piece)| piece.clone()),
+                        );
+                        next.push(combined);
+                        let books_are_free = config.books_free && !left.is_item && !right.is_item;
+                        try_steps(
+                            &next,
+                            rename_pending && !rename,
+                            &[merge_cost],
+                            !books_are_free,
+                            0,
+                        );
+                    }
+                }
+            }
+        }
+        best
+    }
+
+    fn next_random(state: &mut u64) -> u64 {
+        *state = state
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1);
+        *state
+    }
+
+    fn random_enchanted_pieces(random_state: &mut u64) -> Vec<Piece> {
+        let piece_count = 2 + usize::try_from(next_random(random_state) % 5).unwrap();
+        let mut pieces = Vec::with_capacity(piece_count);
+        for index in 0..piece_count {
+            let is_item = index == 0 || next_random(random_state) % 4 == 0;
+            let mut enchantments = Vec::new();
+            for id in 0..3 {
+                if next_random(random_state) % 3 == 0 {
+                    let level = Level::try_from(next_random(random_state) % 3).unwrap();
+                    enchantments.push((id, level + 1));
+                }
+            }
+            let mut piece = enchanted_piece(
+                index,
+                is_item,
+                &enchantments,
+                WorkCount::try_from(next_random(random_state) % 2).unwrap(),
+            );
+            piece.value = Value::try_from(next_random(random_state) % 4).unwrap();
+            piece.item_value = 2 * piece.value;
+            pieces.push(piece);
+        }
+        pieces
+    }
+
+    fn assert_solve_matches_brute_force(config: &Config, pieces: &[Piece], case: usize) {
+        let expected = brute_force(config, pieces);
+        let (actual, trace) = solve(config, pieces).unwrap();
+        let trace_cost = trace
+            .iter()
+            .map(|record| {
+                let material_cost = config
+                    .tome
+                    .as_ref()
+                    .filter(|_| record.tome)
+                    .map_or(0, |tome| tome.material_cost);
+                let wear_cost = if uses_anvil(config, record) {
+                    config.anvil_wear_cost
+                } else {
+                    0
+                };
+                perform(config, record).1 + wear_cost + material_cost
+            })
+            .sum::<Cost>();
+        assert_eq!(actual, expected, "random case {case}, {config:?}");
+        assert_eq!(trace_cost, actual, "random case {case}, {config:?}");
+        // A tome's book going back onto its item doesn't merge input pieces.
+        let merges = trace
+            .iter()
+            .filter(|record| {
+                record
+                    .right
+                    .as_ref()
+                    .is_some_and(|right| !right.name_indices.is_empty())
+            })
+            .count();
+        assert_eq!(merges, pieces.len() - 1);
+    }
+
+    #[test]
+    fn grouped_dp_matches_brute_force() {
+        let mut random_state = 0xA11C_E5EED;
+        for case in 0..24 {
+            let piece_count = 2 + usize::try_from(next_random(&mut random_state) % 6).unwrap();
+            let mut pieces = Vec::with_capacity(piece_count);
+            for index in 0..piece_count {
+                let is_item = index == 0 || next_random(&mut random_state) % 5 == 0;
+                let piece_type = if is_item {
+                    PIECE_TYPE_ITEM
+                } else {
+                    PIECE_TYPE_BOOK
+                };
+                pieces.push(piece(
+                    index,
+                    piece_type,
+                    Value::try_from(next_random(&mut random_state) % 13).unwrap(),
+                    WorkCount::try_from(next_random(&mut random_state) % 3).unwrap(),
+                ));
+            }
+
+            for books_free in [false, true] {
+                for optimize_per_step in [false, true] {
+                    for max_step_cost in [None, Some(16)] {
+                        let config = Config {
+                            max_step_cost,
+                            ..config(books_free, optimize_per_step)
+                        };
+                        let expected = brute_force(&config, &pieces);
+                        let Ok((actual, trace)) = solve(&config, &pieces) else {
+                            assert_eq!(expected, Cost::MAX, "random case {case}, {config:?}");
+                            continue;
+                        };
+                        let trace_cost = trace
+                            .iter()
+                            .map(|record| perform(&config, record).1)
+                            .sum::<Cost>();
+                        assert_eq!(actual, expected, "random case {case}, {config:?}");
+                        assert_eq!(trace_cost, actual, "random case {case}, {config:?}");
+                        assert_eq!(trace.len(), piece_count - 1);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn grouped_dp_collapses_and_solves_the_21_piece_workload() {
+        let values = [
+            0, 12, 12, 4, 6, 4, 3, 2, 12, 8, 8, 4, 4, 4, 4, 6, 2, 5, 6, 4, 6,
+        ];
+        let pieces = values
+            .into_iter()
+            .enumerate()
+            .map(|(index, value)| piece(index, index == 0, value, 0))
+            .collect::<Vec<_>>();
+        let config = config(false, true);
+
+        let (groups, state_count) = group_pieces(&pieces);
+        assert_eq!(groups.len(), 8);
+        assert_eq!(state_count, 11_520);
+
+        let (cost, trace) = solve(&config, &pieces).unwrap();
+        assert_eq!(cost, 12_415);
+        assert_eq!(trace.len(), pieces.len() - 1);
+        let final_record = trace.last().unwrap();
+        let mut final_names = final_record.left.name_indices.clone();
+        final_names.extend_from_slice(&final_record.right.as_ref().unwrap().name_indices);
+        final_names.sort_unstable();
+        assert_eq!(final_names, (0..pieces.len()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn grouped_dp_supports_piece_counts_beyond_fixed_width_encodings() {
+        let pieces = (0..300)
+            .map(|index| piece(index, index == 0, u32::from(index != 0), 0))
+            .collect::<Vec<_>>();
+        let config = config(false, false);
+
+        let (groups, state_count) = group_pieces(&pieces);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(state_count, 600);
+
+        let (_, trace) = solve(&config, &pieces).unwrap();
+        assert_eq!(trace.len(), pieces.len() - 1);
+        let final_record = trace.last().unwrap();
+        let mut final_names = final_record.left.name_indices.clone();
+        final_names.extend_from_slice(&final_record.right.as_ref().unwrap().name_indices);
+        final_names.sort_unstable();
+        assert_eq!(final_names, (0..pieces.len()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn public_api_accepts_more_than_21_pieces() {
+        let schema = ConfigSchema {
+            config: config(false, false),
+            input: Input {
+                items: vec![input_piece("item", "0x0", 0)],
+                books: (0..21)
+                    .map(|index| input_piece(&format!("book {index}"), "1x1", 0))
+                    .collect(),
+            },
+        };
+
+        let result = process(schema).unwrap();
+        assert!(result.contains("Total cost:"));
+        assert!(result.contains("book 20"));
+    }
+
+    #[test]
+    fn displayed_total_cost_matches_the_optimization_mode() {
+        let vanilla = XpCurve::default();
+        assert_eq!(
+            displayed_total_cost(&vanilla, false, 66, 1_246),
+            (66, 11_097)
+        );
+        assert_eq!(displayed_total_cost(&vanilla, true, 66, 1_246), (29, 1_246));
+    }
+
+    #[test]
+    fn xp_point_optimization_can_choose_a_different_order() {
+        let pieces = [
+            piece(0, PIECE_TYPE_ITEM, 0, 0),
+            piece(1, PIECE_TYPE_BOOK, 1, 0),
+            piece(2, PIECE_TYPE_BOOK, 4, 0),
+            piece(3, PIECE_TYPE_BOOK, 4, 0),
+        ];
+        let levels_config = config(false, false);
+        let xp_points_config = config(false, true);
+
+        let (level_objective, level_trace) = solve(&levels_config, &pieces).unwrap();
+        let (xp_objective, xp_trace) = solve(&xp_points_config, &pieces).unwrap();
+        let trace_costs = |trace: &[TraceRecord]| {
+            trace
+                .iter()
+                .map(|record| perform(&levels_config, record).1)
+                .fold((0, 0), |(levels, xp_points), level_cost| {
+                    (levels + level_cost, xp_points + calc_xp(level_cost))
+                })
+        };
+
+        assert_eq!(trace_costs(&level_trace), (12, 138));
+        assert_eq!(trace_costs(&xp_trace), (13, 135));
+        assert_eq!(level_objective, 12);
+        assert_eq!(xp_objective, 135);
+    }
+
+    #[test]
+    fn step_cost_cap_rejects_too_expensive_orders() {
+        let pieces = [
+            piece(0, PIECE_TYPE_ITEM, 0, 0),
+            piece(1, PIECE_TYPE_BOOK, 1, 0),
+            piece(2, PIECE_TYPE_BOOK, 4, 0),
+            piece(3, PIECE_TYPE_BOOK, 6, 0),
+            piece(4, PIECE_TYPE_BOOK, 6, 0),
+        ];
+        let uncapped = config(false, false);
+        let capped = Config {
+            max_step_cost: Some(8),
+            ..uncapped.clone()
+        };
+        let max_step = |config: &Config, trace: &[TraceRecord]| {
+            trace
+                .iter()
+                .map(|record| perform(config, record).1)
+                .max()
+                .unwrap()
+        };
+
+        let (uncapped_cost, uncapped_trace) = solve(&uncapped, &pieces).unwrap();
+        assert_eq!(uncapped_cost, 23);
+        assert!(max_step(&uncapped, &uncapped_trace) > 8);
+
+        let (capped_cost, capped_trace) = solve(&capped, &pieces).unwrap();
+        assert_eq!(capped_cost, 28);
+        assert!(max_step(&capped, &capped_trace) <= 8);
+
+        let tight = Config {
+            max_step_cost: Some(5),
+            ..capped
+        };
+        assert_eq!(solve(&tight, &pieces).unwrap_err(), SolveError::Infeasible);
+    }
+
+    #[test]
+    fn infeasible_report_names_the_cheapest_failing_subtree() {
+        let schema = ConfigSchema {
+            config: Config {
+                max_step_cost: Some(DEFAULT_MAX_STEP_COST),
+                ..config(false, false)
+            },
+            input: Input {
+                items: vec![input_piece("sword", "0x0", 0)],
+                books: vec![
+                    input_piece("sharpness", "5x1", 0),
+                    input_piece("heavy", "40x1", 0),
+                ],
+            },
+        };
+
+        let result = process(schema).unwrap();
+        assert!(result.starts_with("No feasible order"));
+        assert!(result.contains("Cheapest failing subtree:\n"));
+        assert!(result.contains("[heavy: 40,0] = 4"));
+    }
+
+    #[test]
+    fn enchanted_dp_matches_brute_force() {
+        let mut random_state = 0x5EED_1E7E1;
+        for case in 0..24 {
+            let pieces = random_enchanted_pieces(&mut random_state);
+            for books_free in [false, true] {
+                for optimize_per_step in [false, true] {
+                    let config = config(books_free, optimize_per_step);
+                    assert_solve_matches_brute_force(&config, &pieces, case);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn equal_levels_merge_into_the_next_level() {
+        let merge = |target: Level, sacrifice: Level| {
+            let (merged, cost) = merge_enchantments(
+                Edition::Java,
+                &enchanted_piece(0, PIECE_TYPE_BOOK, &[(1, target)], 0).enchantments,
+                &enchanted_piece(1, PIECE_TYPE_BOOK, &[(1, sacrifice)], 0).enchantments,
+                PIECE_TYPE_BOOK,
+                PIECE_TYPE_BOOK,
+            );
+            (merged[0].level, cost)
+        };
+        assert_eq!(merge(2, 2), (3, 6));
+        assert_eq!(merge(3, 1), (3, 6));
+        assert_eq!(merge(1, 3), (3, 6));
+        assert_eq!(merge(4, 4), (4, 8));
+
+        let schema = ConfigSchema {
+            config: Config {
+                enchantments: BTreeMap::from([(
+                    String::from("sharpness"),
+                    EnchantmentConfig {
+                        max_level: 5,
+                        multipliers: None,
+                        applies_to: Vec::new(),
+                    },
+                )]),
+                ..config(false, false)
+            },
+            input: Input {
+                items: vec![input_piece("sword", "0x0", 0)],
+                books: vec![
+                    input_piece("sharpness", "4x1", 0),
+                    input_piece("sharpness", "4x1", 0),
+                ],
+            },
+        };
+        // IV then IV again upgrades to V: 4 levels, then 5 + 1 penalty.
+        let result = process(schema).unwrap();
+        assert!(result.contains("[sword + sharpness: 4,1] + [sharpness: 4,0] = 6 lvl"));
+        assert!(result.contains("Total cost: 10 lvl"));
+    }
+
+    #[test]
+    fn enchantment_levels_above_the_maximum_are_rejected() {
+        let schema = ConfigSchema {
+            config: Config {
+                enchantments: BTreeMap::from([(
+                    String::from("mending"),
+                    EnchantmentConfig {
+                        max_level: 1,
+                        multipliers: None,
+                        applies_to: Vec::new(),
+                    },
+                )]),
+                ..config(false, false)
+            },
+            input: Input {
+                items: vec![input_piece("pickaxe", "0x0", 0)],
+                books: vec![input_piece("mending", "2x2", 0)],
+            },
+        };
+        assert_eq!(
+            process(schema).unwrap_err(),
+            "mending: level 2 exceeds max level 1"
+        );
+    }
+
+    #[test]
+    fn incompatible_enchantments_cost_a_level_each_and_are_dropped() {
+        let target = enchanted_piece(0, PIECE_TYPE_ITEM, &[(0, 1), (1, 2)], 0);
+        let sacrifice = enchanted_piece(1, PIECE_TYPE_BOOK, &[(0, 1), (2, 3)], 0);
+        let (merged, cost) = merge_enchantments(
+            Edition::Java,
+            &target.enchantments,
+            &sacrifice.enchantments,
+            PIECE_TYPE_ITEM,
+            PIECE_TYPE_BOOK,
+        );
+        let levels = merged
+            .iter()
+            .map(|enchantment| (enchantment.id, enchantment.level))
+            .collect::<Vec<_>>();
+        // Enchantment 0 upgrades to level 2 for 2 x 1, enchantment 2 clashes
+        // with enchantment 1 for a single level.
+        assert_eq!(levels, [(0, 2), (1, 2)]);
+        assert_eq!(cost, 3);
+    }
+
+    #[test]
+    fn conflicting_input_enchantments_are_rejected() {
+        let schema = ConfigSchema {
+            config: Config {
+                enchantments: ["protection", "blast protection", "mending"]
+                    .into_iter()
+                    .map(|name| {
+                        (
+                            String::from(name),
+                            EnchantmentConfig {
+                                max_level: 4,
+                                multipliers: None,
+                                applies_to: Vec::new(),
+                            },
+                        )
+                    })
+                    .collect(),
+                conflicts: vec![vec![
+                    String::from("protection"),
+                    String::from("blast protection"),
+                ]],
+                ..config(false, false)
+            },
+            input: Input {
+                items: vec![input_piece("chestplate", "0x0", 0)],
+                books: vec![
+                    input_piece("protection", "4x1", 0),
+                    input_piece("mending", "1x2", 0),
+                    input_piece("blast protection", "4x2", 0),
+                ],
+            },
+        };
+        assert_eq!(
+            process(schema).unwrap_err(),
+            "blast protection conflicts with protection, so the final item would lose one of them"
+        );
+    }
+
+    #[test]
+    fn item_sacrifices_use_the_item_multiplier_column() {
+        let schema = ConfigSchema {
+            config: config(false, false),
+            input: Input {
+                items: vec![
+                    input_piece("sword a", "2x1/2", 0),
+                    input_piece("sword b", "3x1/2", 0),
+                ],
+                books: vec![input_piece("looting", "3x2/4", 0)],
+            },
+        };
+        let result = process(schema).unwrap();
+        // Sacrificing sword a costs 2 x 2 plus the penalty, the book only 3 x 2.
+        assert!(result.contains("[sword a: 4,0] = 5 lvl"));
+        assert!(result.contains("[looting: 6,0] = "));
+        assert_eq!(parse_level_multiplier("book", "2x3x2"), Ok((2, 6, 6)));
+        assert_eq!(parse_level_multiplier("book", "3x4/8"), Ok((3, 4, 8)));
+    }
+
+    #[test]
+    fn bedrock_dp_matches_brute_force() {
+        let mut random_state = 0xBED_0C4;
+        for case in 0..24 {
+            let pieces = random_enchanted_pieces(&mut random_state);
+            for books_free in [false, true] {
+                for optimize_per_step in [false, true] {
+                    let config = Config {
+                        edition: Edition::Bedrock,
+                        ..config(books_free, optimize_per_step)
+                    };
+                    assert_solve_matches_brute_force(&config, &pieces, case);
+                }
+            }
+        }
+
+        // Books giving just their level take Bedrock's cost table.
+        let schema: ConfigSchema = yaml_serde::from_str(
+            "config:
+  books_free: false
+  optimize_per_step: false
+  edition: bedrock
+  game_version: \"1.21\"
+input:
+  items:
+    - { name: trident, type: trident, enchantments: { impaling: \"2\" } }
+  books:
+    - [impaling, \"3\", 0]
+    - [impaling, \"3\", 0]
+    - [loyalty, \"3\", 0]
+    - [unbreaking, \"2\", 1]
+    - [mending, \"1\", 0]
+",
+        )
+        .unwrap();
+        let profile = apply_game_version(schema.config).unwrap();
+        assert_eq!(profile.enchantments["impaling"].multipliers, Some((1, 2)));
+        assert_eq!(profile.enchantments["mending"].multipliers, Some((2, 4)));
+        assert!(!profile.enchantments.contains_key("sweeping edge"));
+        let (pieces, _) = build_pieces(&profile, &schema.input).unwrap();
+        for optimize_per_step in [false, true] {
+            let config = Config {
+                optimize_per_step,
+                ..profile.clone()
+            };
+            assert_solve_matches_brute_force(&config, &pieces, 0);
+        }
+    }
+
+    #[test]
+    fn bedrock_charges_only_the_levels_a_sacrifice_adds() {
+        let merge = |edition, target: &[(usize, Level)], sacrifice: &[(usize, Level)]| {
+            merge_enchantments(
+                edition,
+                &enchanted_piece(0, PIECE_TYPE_ITEM, target, 0).enchantments,
+                &enchanted_piece(1, PIECE_TYPE_BOOK, sacrifice, 0).enchantments,
+                PIECE_TYPE_ITEM,
+                PIECE_TYPE_BOOK,
+            )
+            .1
+        };
+        // Enchantment 0 goes from level 2 to 3 at a multiplier of 1.
+        assert_eq!(merge(Edition::Java, &[(0, 2)], &[(0, 2)]), 3);
+        assert_eq!(merge(Edition::Bedrock, &[(0, 2)], &[(0, 2)]), 1);
+        assert_eq!(merge(Edition::Bedrock, &[(0, 3)], &[(0, 1)]), 0);
+        // Enchantment 2 clashes with enchantment 1 and is dropped.
+        assert_eq!(merge(Edition::Java, &[(1, 1)], &[(2, 1)]), 1);
+        assert_eq!(merge(Edition::Bedrock, &[(1, 1)], &[(2, 1)]), 0);
+    }
+
+    #[test]
+    fn renamed_dp_matches_brute_force() {
+        let mut random_state = 0x4E_A4E;
+        for case in 0..24 {
+            let mut pieces = random_enchanted_pieces(&mut random_state);
+            // Renaming widens the brute force search, so keep it small.
+            pieces.truncate(5);
+            pieces[0].rename = true;
+            for books_free in [false, true] {
+                for optimize_per_step in [false, true] {
+                    let config = config(books_free, optimize_per_step);
+                    assert_solve_matches_brute_force(&config, &pieces, case);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rename_only_steps_are_capped_and_add_no_work() {
+        let config = config(false, false);
+        let record = TraceRecord {
+            left: piece(0, PIECE_TYPE_ITEM, 0, 6),
+            right: None,
+            rename: true,
+            material_repair: false,
+            tome: false,
+        };
+        let (renamed, cost) = perform(&config, &record);
+        assert_eq!(cost, MAX_RENAME_ONLY_COST);
+        assert_eq!(renamed.work_count, 6);
+        let bedrock = Config {
+            edition: Edition::Bedrock,
+            ..config
+        };
+        assert_eq!(perform(&bedrock, &record).1, 64);
+    }
+
+    #[test]
+    fn report_shows_where_the_rename_happens() {
+        let schema = |optimize_per_step, penalty| -> ConfigSchema {
+            yaml_serde::from_str(&format!(
+                "config:
+  books_free: false
+  optimize_per_step: {optimize_per_step}
+input:
+  items:
+    - name: sword
+      penalty: {penalty}
+      rename: true
+  books:
+    - [sharpness, 1x1, 0]
+"
+            ))
+            .unwrap()
+        };
+        // With prior work, a standalone rename costs more than riding along.
+        let result = process(schema(false, 1)).unwrap();
+        assert!(result.contains("1. [sword: 0,1] + [sharpness: 1,0] + rename = 3 lvl"));
+        // At one level each, two small steps take fewer XP points than one.
+        let result = process(schema(true, 0)).unwrap();
+        assert!(result.contains("1. [sword: 0,0] + rename = 1 lvl (7 xp)"));
+        assert!(result.contains("Total cost: 2 lvl (14 xp)"));
+    }
+
+    #[test]
+    fn worn_dp_matches_brute_force() {
+        let mut random_state = 0xD_A4A6E;
+        for case in 0..24 {
+            let mut pieces = random_enchanted_pieces(&mut random_state);
+            pieces.truncate(5);
+            pieces[0].rename = case % 3 == 0;
+            let material = case % 2 == 0;
+            for piece in pieces.iter_mut().filter(|piece| piece.is_item) {
+                let damage = Value::try_from(next_random(&mut random_state) % 100).unwrap();
+                piece.durability = Some(Durability {
+                    damage,
+                    max: 100,
+                    material,
+                });
+            }
+            for optimize_per_step in [false, true] {
+                let config = config(false, optimize_per_step);
+                assert_solve_matches_brute_force(&config, &pieces, case);
+            }
+        }
+    }
+
+    #[test]
+    fn item_sacrifices_repair_at_two_levels() {
+        let worn = |index, damage| Piece {
+            durability: Some(Durability {
+                damage,
+                max: 100,
+                material: false,
+            }),
+            ..piece(index, PIECE_TYPE_ITEM, 0, 0)
+        };
+        let config = config(false, false);
+        // 40 remaining on the sacrifice plus a 12 bonus.
+        let (repaired, cost) = anvil(&config, &worn(0, 70), &worn(1, 60), false);
+        assert_eq!(repaired.durability.unwrap().damage, 18);
+        assert_eq!(cost, 2);
+        let (repaired, cost) = anvil(&config, &worn(0, 0), &worn(1, 60), false);
+        assert_eq!(repaired.durability.unwrap().damage, 0);
+        assert_eq!(cost, 0);
+        assert_eq!(material_units(1_500, 2_031), 3);
+        assert_eq!(material_units(2_031, 2_031), 5);
+    }
+
+    #[test]
+    fn worn_item_is_repaired_with_material() {
+        let schema: ConfigSchema = yaml_serde::from_str(
+            "config:
+  books_free: false
+  optimize_per_step: false
+input:
+  items:
+    - name: pickaxe
+      durability: { damage: 1500, max: 2031 }
+  books:
+    - [efficiency, 5x1, 0]
+    - [unbreaking, 3x1, 0]
+",
+        )
+        .unwrap();
+        let result = process(schema).unwrap();
+        assert!(result.contains(" + 3 material = "));
+        assert!(result.contains("Final durability: 2031/2031\n"));
+    }
+
+    #[test]
+    fn enchanted_items_are_only_charged_for_what_books_add() {
+        let schema = |sword: &str| -> Result<String, String> {
+            process(
+                yaml_serde::from_str(&format!(
+                    "config:
+  books_free: false
+  optimize_per_step: false
+  enchantments:
+    sharpness: {{ max_level: 5 }}
+    unbreaking: {{ max_level: 3 }}
+input:
+  items:
+    - {sword}
+  books:
+    - [sharpness, 4x1, 0]
+"
+                ))
+                .unwrap(),
+            )
+        };
+        // Sharpness IV upgrades to V for 5 levels, unbreaking is left alone.
+        let result =
+            schema("{ name: sword, enchantments: { sharpness: 4x1, unbreaking: 3x1/2 } }").unwrap();
+        assert!(result.contains("[sword: 10,0] + [sharpness: 4,0] = 5 lvl"));
+        assert_eq!(
+            schema("{ name: sword, enchantments: { mending: 1x2 } }").unwrap_err(),
+            "sword: mending is not listed in config.enchantments"
+        );
+    }
+
+    #[test]
+    fn penalty_curves_match_brute_force() {
+        let curves = [
+            PenaltyCurve::Preset(PenaltyPreset::Linear),
+            PenaltyCurve::Preset(PenaltyPreset::None),
+            PenaltyCurve::Table(vec![0, 2, 2, 5]),
+        ];
+        let mut random_state = 0xC0_4E5;
+        for case in 0..24 {
+            let pieces = random_enchanted_pieces(&mut random_state);
+            for penalty_curve in &curves {
+                for optimize_per_step in [false, true] {
+                    let config = Config {
+                        penalty_curve: penalty_curve.clone(),
+                        ..config(false, optimize_per_step)
+                    };
+                    assert_solve_matches_brute_force(&config, &pieces, case);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn penalty_curves_round_input_penalties_up() {
+        let vanilla = PenaltyCurve::default();
+        assert_eq!(vanilla.work_count(3), 2);
+        assert_eq!(vanilla.work_count(4), 3);
+        let table = PenaltyCurve::Table(vec![0, 2, 2, 5]);
+        assert_eq!(table.work_count(1), 1);
+        assert_eq!(table.work_count(3), 3);
+        assert_eq!(table.work_count(9), 3);
+        assert_eq!(table.penalty(7), 5);
+        let none = PenaltyCurve::Preset(PenaltyPreset::None);
+        assert_eq!(none.work_count(31), 0);
+        assert!(PenaltyCurve::Table(vec![0, 3, 1]).check().is_err());
+
+        let schema: ConfigSchema = yaml_serde::from_str(
+            "config:
+  books_free: false
+  optimize_per_step: false
+  penalty_curve: linear
+input:
+  items:
+    - [sword, 0x0, 3]
+  books:
+    - [sharpness, 5x1, 0]
+",
+        )
+        .unwrap();
+        assert!(
+            process(schema)
+                .unwrap()
+                .contains("[sword: 0,3] + [sharpness: 5,0] = 8 lvl")
+        );
+    }
+
+    #[test]
+    fn large_penalties_map_to_work_counts() {
+        let vanilla = PenaltyCurve::default();
+        assert_eq!(vanilla.work_count(MAX_REPAIR_COST), 31);
+        assert_eq!(vanilla.penalty(200), MAX_REPAIR_COST);
+        let linear = PenaltyCurve::Preset(PenaltyPreset::Linear);
+        assert_eq!(linear.work_count(1_000), 1_000);
+
+        let schema = |max_step_cost: &str, penalty_curve: &str, penalty: &str| {
+            process(
+                yaml_serde::from_str(&format!(
+                    "config:
+  books_free: false
+  optimize_per_step: false
+  max_step_cost: {max_step_cost}
+  penalty_curve: {penalty_curve}
+input:
+  items:
+    - [sword, 0x0, {penalty}]
+  books:
+    - [sharpness, 5x1, 0]
+"
+                ))
+                .unwrap(),
+            )
+        };
+        assert!(
+            schema("~", "vanilla", "1073741823")
+                .unwrap()
+                .contains("[sword: 0,1073741823] + [sharpness: 5,0] = 1073741828 lvl")
+        );
+        // The XP of a step this expensive is more than can be counted, but
+        // its levels still are.
+        assert!(schema("~", "vanilla", "2147483647").unwrap().contains(
+            "[sword: 0,2147483647] + [sharpness: 5,0] = 2147483652 lvl (uncountable xp)"
+        ));
+        assert!(
+            schema("39", "vanilla", "2147483647")
+                .unwrap()
+                .starts_with("No feasible order")
+        );
+        // Linear work counts this high don't fit, capped or not.
+        for max_step_cost in ["39", "~"] {
+            assert!(
+                schema(max_step_cost, "linear", "1000000")
+                    .unwrap_err()
+                    .contains("more than the solver tracks")
+            );
+        }
+        assert!(
+            schema("~", "vanilla", "2147483648")
+                .unwrap_err()
+                .contains("the most Java stores")
+        );
+
+        // Vanilla penalties stop growing long before the work counts the
+        // solver tracks, so many pieces need no room for their merges.
+        let result = process(ConfigSchema {
+            config: config(false, false),
+            input: Input {
+                items: vec![input_piece("item", "0x0", 0)],
+                books: (0..300)
+                    .map(|index| input_piece(&format!("book {index}"), "1x1", 0))
+                    .collect(),
+            },
+        })
+        .unwrap();
+        assert!(result.contains("Total cost: 3193 lvl"));
+
+        // Linear penalties keep growing, so every merge needs room.
+        let near_limit = |penalty: &str| {
+            process(
+                yaml_serde::from_str(&format!(
+                    "config:
+  books_free: false
+  optimize_per_step: false
+  max_step_cost: ~
+  penalty_curve: linear
+input:
+  items:
+    - [sword, 0x0, {penalty}]
+  books:
+    - [book, 1x1, {penalty}]
+    - [book, 1x1, {penalty}]
+    - [book, 1x1, {penalty}]
+"
+                ))
+                .unwrap(),
+            )
+        };
+        assert!(near_limit("252").unwrap().contains("Final penalty: 254"));
+        assert!(
+            near_limit("254")
+                .unwrap_err()
+                .contains("an order can take a piece to 257 prior operations")
+        );
+    }
+
+    #[test]
+    fn plans_costing_more_xp_than_can_be_counted_are_marked() {
+        // Pieces at the most Java stores cost billions of levels a step, whose
+        // XP alone doesn't fit a cost, let alone their sum.
+        let schema: ConfigSchema = yaml_serde::from_str(
+            "config:
+  books_free: false
+  optimize_per_step: false
+  max_step_cost: ~
+  starting_level: 0
+input:
+  items:
+    - [sword, 0x0, 2147483647]
+  books:
+    - [a, 1x1, 2147483647]
+    - [b, 1x1, 2147483647]
+",
+        )
+        .unwrap();
+        let result = process(schema).unwrap();
+        assert!(result.contains("Total cost: 8589934590 lvl (uncountable xp)\n"));
+        assert!(result.contains("XP drained: uncountable xp\n"));
+    }
+
+    #[test]
+    fn xp_curves_price_levels() {
+        let legacy = XpCurve::Preset(XpPreset::Legacy);
+        assert_eq!(legacy.xp(15), 255);
+        assert_eq!(legacy.xp(16), 272);
+        assert_eq!(legacy.xp(31), 887);
+        assert_eq!(legacy.level(273), 17);
+
+        let segments = XpCurve::Segments(vec![
+            XpSegment {
+                from: 0,
+                polynomial: vec![0.0, 6.0, 1.0],
+            },
+            XpSegment {
+                from: 16,
+                polynomial: vec![360.0, -40.5, 2.5],
+            },
+            XpSegment {
+                from: 32,
+                polynomial: vec![2_220.0, -162.5, 4.5],
+            },
+        ]);
+        assert_eq!(segments.check(), Ok(()));
+        for level in 0..60 {
+            assert_eq!(segments.xp(level), calc_xp(level), "level {level}");
+        }
+
+        let table = XpCurve::Table(vec![0, 7, 16]);
+        assert_eq!(table.xp(4), 34);
+        assert_eq!(table.level(20), 3);
+        assert!(XpCurve::Table(vec![0, 7, 7]).check().is_err());
+
+        let schema: ConfigSchema = yaml_serde::from_str(
+            "config:
+  books_free: false
+  optimize_per_step: false
+  xp_curve: pre-1.8
+input:
+  items:
+    - [sword, 0x0, 0]
+  books:
+    - [sharpness, 5x1, 0]
+",
+        )
+        .unwrap();
+        assert!(
+            process(schema)
+                .unwrap()
+                .contains("Total cost: 5 lvl (85 xp)")
+        );
+    }
+
+    #[test]
+    fn xp_curves_match_brute_force() {
+        let curves = [
+            XpCurve::Preset(XpPreset::Legacy),
+            XpCurve::Table(vec![0, 10, 25, 45, 70]),
+        ];
+        let mut random_state = 0x0_C4_4E;
+        for case in 0..24 {
+            let pieces = random_enchanted_pieces(&mut random_state);
+            for xp_curve in &curves {
+                let config = Config {
+                    xp_curve: xp_curve.clone(),
+                    ..config(false, true)
+                };
+                assert_solve_matches_brute_force(&config, &pieces, case);
+            }
+        }
+    }
+
+    #[test]
+    fn library_withdraws_the_fewest_books_for_the_best_level() {
+        let library = |books: &str| {
+            process(
+                yaml_serde::from_str(&format!(
+                    "config:
+  books_free: false
+  optimize_per_step: false
+  library: {{}}
+  enchantments:
+    sharpness: {{ max_level: 5 }}
+input:
+  items:
+    - [sword, 0x0, 0]
+  books:
+{books}"
+                ))
+                .unwrap(),
+            )
+            .unwrap()
+        };
+        // 4 + 4 + 8 points pay for V, but only IV was ever deposited.
+        let result = library(
+            "    - [sharpness, 3x1, 0]\n    - [sharpness, 3x1, 0]\n    - [sharpness, 4x1, 0]\n",
+        );
+        assert!(result.starts_with(
+            "Deposit in the library: sharpness 3, sharpness 3, sharpness 4\n\
+             Withdraw: 2 x sharpness 4\n\n"
+        ));
+        assert!(result.contains("Total cost: 10 lvl (160 xp)"));
+        let result = library("    - [sharpness, 4x1, 0]\n    - [sharpness, 1x1, 0]\n");
+        assert!(result.contains("Withdraw: sharpness 4\nPoints left: sharpness 1\n"));
+        assert!(result.contains("[sword: 0,0] + [sharpness: 4,0] = 4 lvl"));
+    }
+
+    #[test]
+    fn anvil_wear_is_reported_and_priced() {
+        let schema = yaml_serde::from_str(
+            "config:
+  books_free: false
+  optimize_per_step: false
+input:
+  items:
+    - [sword, 0x0, 0]
+  books:
+    - [sharpness, 4x1, 0]
+    - [unbreaking, 3x1, 0]
+",
+        )
+        .unwrap();
+        assert!(process(schema).unwrap().ends_with(
+            "Anvil wear: 0.24 damage stages over 2 uses (0.24 iron blocks + 0.32 iron ingots)\n"
+        ));
+
+        let mut random_state = 0xA_4F11;
+        for case in 0..12 {
+            let mut pieces = random_enchanted_pieces(&mut random_state);
+            pieces.truncate(4);
+            pieces[0].rename = case % 2 == 0;
+            for piece in pieces.iter_mut().filter(|piece| piece.is_item) {
+                piece.durability = Some(Durability {
+                    damage: Value::try_from(next_random(&mut random_state) % 100).unwrap(),
+                    max: 100,
+                    material: true,
+                });
+            }
+            let config = Config {
+                anvil_wear_cost: 5,
+                ..config(case % 3 == 0, false)
+            };
+            assert_solve_matches_brute_force(&config, &pieces, case);
+        }
+    }
+
+    #[test]
+    fn anvil_wear_skips_free_book_merges() {
+        let schema = |anvil_wear_cost: Cost| {
+            yaml_serde::from_str(&format!(
+                "config:
+  books_free: true
+  optimize_per_step: true
+  max_step_cost: ~
+  anvil_wear_cost: {anvil_wear_cost}
+input:
+  items:
+    - [sword, 0x0, 0]
+  books:
+    - [book a, 10x1, 0]
+    - [book b, 10x1, 0]
+    - [book c, 10x1, 0]
+    - [book d, 10x1, 0]
+"
+            ))
+            .unwrap()
+        };
+        // Without wear, three small steps take fewer XP points than one.
+        let result = process(schema(0)).unwrap();
+        assert!(result.contains("Total cost: 26 lvl (984 xp)\n"));
+        assert!(result.contains("over 3 uses"));
+        // Combining the books first is free and leaves a single anvil use.
+        let result = process(schema(2_000)).unwrap();
+        assert!(result.contains(
+            "4. [sword: 0,0] + [book a + book b + book c + book d: 40,0] = 40 lvl (2920 xp)\n"
+        ));
+        assert!(result.contains("over 1 uses"));
+    }
+
+    #[test]
+    fn items_of_different_types_are_rejected() {
+        let schema = |items: &str| -> Result<String, String> {
+            process(
+                yaml_serde::from_str(&format!(
+                    "config:
+  books_free: false
+  optimize_per_step: false
+input:
+  items:
+{items}  books:
+    - [sharpness, 4x1, 0]
+"
+                ))
+                .unwrap(),
+            )
+        };
+        assert!(
+            schema(
+                "    - { name: sword, type: diamond_sword }\n    \
+                 - { name: old sword, value: 3x1, type: diamond_sword }\n"
+            )
+            .is_ok()
+        );
+        assert_eq!(
+            schema("    - { name: sword, type: diamond_sword }\n    - [old sword, 3x1, 0]\n")
+                .unwrap_err(),
+            "old sword: needs a type to be combined with a diamond_sword"
+        );
+        assert!(schema("    - [sword, 0x0, 0]\n    - [old sword, 3x1, 0]\n").is_ok());
+        assert_eq!(
+            schema(
+                "    - { name: sword, type: diamond_sword }\n    \
+                 - { name: pickaxe, type: diamond_pickaxe }\n"
+            )
+            .unwrap_err(),
+            "pickaxe: a diamond_pickaxe can't be combined with a diamond_sword"
+        );
+    }
+
+    #[test]
+    fn game_versions_select_the_enchantment_registry() {
+        assert_eq!(
+            GameVersion::try_from(String::from("1.20.5")),
+            Ok(GameVersion(1, 20, 5))
+        );
+        assert_eq!(String::from(GameVersion(1, 21, 0)), "1.21");
+        assert_eq!(
+            GameVersion::try_from(String::from("1")).unwrap_err(),
+            "game_version: invalid version \"1\""
+        );
+        let profile = |version: &str| {
+            apply_game_version(Config {
+                game_version: Some(GameVersion::try_from(String::from(version)).unwrap()),
+                ..config(false, false)
+            })
+        };
+        assert_eq!(
+            profile("1.7.10").unwrap_err(),
+            "game_version: anvil rules before 1.8 are not supported"
+        );
+        let old = profile("1.8.9").unwrap();
+        assert!(!old.enchantments.contains_key("mending"));
+        assert!(old.conflicts.contains(&vec![
+            String::from("sharpness"),
+            String::from("smite"),
+            String::from("bane of arthropods"),
+        ]));
+        let new = profile("1.21").unwrap();
+        assert_eq!(new.enchantments["mending"].multipliers, Some((2, 4)));
+        assert!(new.conflicts.iter().any(|group| group.len() == 5));
+        assert!(!new.conflicts.iter().any(|group| group.len() == 3));
+        let bedrock = |version: &str| {
+            apply_game_version(Config {
+                edition: Edition::Bedrock,
+                game_version: Some(GameVersion::try_from(String::from(version)).unwrap()),
+                ..config(false, false)
+            })
+        };
+        assert_eq!(
+            bedrock("1.14").unwrap_err(),
+            "game_version: anvil rules before 1.16 are not supported"
+        );
+        assert_eq!(
+            bedrock("1.21").unwrap().enchantments["impaling"].multipliers,
+            Some((1, 2))
+        );
+
+        // Bare levels take their multipliers from the cost table.
+        let schema = yaml_serde::from_str(
+            "config:
+  books_free: false
+  optimize_per_step: false
+  game_version: \"1.21\"
+input:
+  items:
+    - { name: sword, enchantments: { smite: \"4\" } }
+  books:
+    - [smite, \"4\", 0]
+",
+        )
+        .unwrap();
+        assert!(
+            process(schema)
+                .unwrap()
+                .contains("[sword: 8,0] + [smite: 4,0] = 5 lvl")
+        );
+
+        // Configured enchantments keep the table's multipliers, and an explicit
+        // book multiplier agrees with a bare level.
+        let schema = yaml_serde::from_str(
+            "config:
+  books_free: false
+  optimize_per_step: false
+  game_version: \"1.21\"
+  enchantments:
+    soul speed: { max_level: 3, applies_to: [sword] }
+input:
+  items:
+    - [sword, 0x0, 0]
+  books:
+    - [soul speed, \"3\", 0]
+    - [soul speed, 3x4, 0]
+",
+        )
+        .unwrap();
+        let result = process(schema).unwrap();
+        assert!(result.contains("[soul speed: 12,0] + [soul speed: 12,0] = 12 lvl"));
+        assert!(result.contains("Total cost: 25 lvl"));
+    }
+
+    #[test]
+    fn rulesets_match_brute_force() {
+        let rulesets = [
+            Ruleset::Apotheosis,
+            Ruleset::Quark,
+            Ruleset::EasyAnvils,
+            Ruleset::AnvilRestored,
+        ];
+        let mut random_state = 0x4E_5E7;
+        for case in 0..24 {
+            let mut pieces = random_enchanted_pieces(&mut random_state);
+            // High penalties make the cap matter.
+            for piece in &mut pieces {
+                piece.work_count += 3;
+            }
+            for ruleset in rulesets {
+                let mut config = config(false, case % 2 == 0);
+                ruleset.apply(&mut config);
+                if solve(&config, &pieces).is_ok() {
+                    assert_solve_matches_brute_force(&config, &pieces, case);
+                } else {
+                    assert_eq!(brute_force(&config, &pieces), Cost::MAX);
+                }
+            }
+        }
+    }
 
-fn get_name(names: &[String], name_indices: &[usize]) -> String {
-    name_indices
-        .iter()
-        .map(|&index| names[index].as_str())
-        .collect::<Vec<_>>()
-        .join(" + ")
-}
+    #[test]
+    fn rulesets_replace_the_options_they_decide() {
+        let schema = |ruleset: &str| {
+            process(
+                yaml_serde::from_str(&format!(
+                    "config:
+  books_free: false
+  optimize_per_step: false
+  ruleset: {ruleset}
+input:
+  items:
+    - [sword, 0x0, 31]
+  books:
+    - [sharpness, 5x1, 31]
+"
+                ))
+                .unwrap(),
+            )
+            .unwrap()
+        };
+        // 5 + 31 + 31 levels are too expensive without a mod.
+        assert!(schema("~").starts_with("No feasible order"));
+        assert!(schema("quark").contains("[sword: 0,31] + [sharpness: 5,31] = 67 lvl"));
+        assert!(schema("anvil_restored").contains("[sword: 0,15] + [sharpness: 5,15] = 35 lvl"));
+    }
 
-const fn expand_cost(config: &Config, cost: Cost) -> (Cost, Cost) {
-    if config.optimize_per_step {
-        (calc_level(cost), cost)
-    } else {
-        (cost, calc_xp(cost))
+    #[test]
+    fn books_that_cannot_apply_to_the_item_are_wasted() {
+        let categories = |categories: &[&str]| {
+            categories
+                .iter()
+                .map(|&category| String::from(category))
+                .collect::<Vec<_>>()
+        };
+        assert!(applies_to(&categories(&["axe"]), "netherite_axe"));
+        assert!(!applies_to(&categories(&["axe"]), "diamond_pickaxe"));
+        assert!(applies_to(&categories(&["*"]), "shield"));
+        assert!(applies_to(&[], "shield"));
+
+        let schema = |enchantments: &str, item: &str| {
+            process(
+                yaml_serde::from_str(&format!(
+                    "config:
+  books_free: false
+  optimize_per_step: false
+  game_version: \"1.21\"
+  enchantments: {{ {enchantments} }}
+input:
+  items:
+    - {{ name: sword, type: diamond_sword{item} }}
+  books:
+    - [sharpness, \"5\", 0]
+    - [soul speed, \"3\", 0]
+"
+                ))
+                .unwrap(),
+            )
+        };
+        let result = schema("", "").unwrap();
+        assert!(result.starts_with(
+            "Wasted books, they can't be applied to a diamond_sword: soul speed\n\n1. "
+        ));
+        assert!(result.contains("[sword: 0,0] + [sharpness: 5,0] = 5 lvl"));
+        // Extending a vanilla enchantment to more item types keeps its
+        // multipliers: soul speed costs 4 per level on a book.
+        let extended = "soul speed: { max_level: 3, applies_to: [sword] }";
+        let result = schema(extended, "").unwrap();
+        assert!(result.starts_with("1. "));
+        assert!(result.contains("[soul speed: 12,0]"));
+        assert!(result.contains("Total cost: 18 lvl"));
+        assert_eq!(
+            schema("", ", enchantments: { feather falling: \"4\" }").unwrap_err(),
+            "sword: feather falling can't be applied to a diamond_sword"
+        );
     }
-}
 
-pub fn process(schema: ConfigSchema) -> String {
-    let (input, config) = (schema.input, schema.config);
+    #[test]
+    fn books_can_carry_several_enchantments() {
+        let schema = |config: &str, book: &str| {
+            process(
+                yaml_serde::from_str(&format!(
+                    "config:
+  books_free: false
+  optimize_per_step: false
+{config}
+input:
+  items:
+    - {{ name: sword, type: diamond_sword }}
+  books:
+    - {{ name: loot book, enchantments: {{ {book} }} }}
+"
+                ))
+                .unwrap(),
+            )
+            .unwrap()
+        };
+        let result = schema(
+            "  enchantments: { sharpness: { max_level: 5 }, unbreaking: { max_level: 3 } }",
+            "sharpness: 4x1, unbreaking: 3x1/2",
+        );
+        assert!(
+            result.contains("[sword: 0,0] + [loot book (sharpness 4, unbreaking 3): 7,0] = 7 lvl")
+        );
+        // The item skips what it can't hold, free of charge.
+        let result = schema(
+            "  game_version: \"1.21\"",
+            "sharpness: \"5\", soul speed: \"3\"",
+        );
+        assert!(result.starts_with(
+            "Wasted books, they can't be applied to a diamond_sword: \
+             soul speed on loot book (sharpness 5, soul speed 3)\n\n"
+        ));
+        assert!(result.contains("Total cost: 5 lvl"));
 
-    let mut pieces = Vec::new();
-    let mut names = Vec::new();
-    let item_iter = input.items.iter().map(|item| (item, PIECE_TYPE_ITEM));
-    let book_iter = input.books.iter().map(|item| (item, PIECE_TYPE_BOOK));
-    for (i, (piece, piece_type)) in item_iter.chain(book_iter).enumerate() {
-        let (name, level_multiplier, penalty) = piece.clone();
-        names.push(name);
-        pieces.push(Piece {
-            name_indices: vec![i],
-            is_item: piece_type,
-            value: level_multiplier
-                .split('x')
-                .map(|component| component.trim().parse::<Value>().unwrap())
-                .product(),
-            work_count: calc_work_count(penalty),
-        });
+        let mut random_state = 0xB0_0C5;
+        for case in 0..24 {
+            let mut pieces = random_enchanted_pieces(&mut random_state);
+            for piece in pieces.iter_mut().filter(|piece| !piece.is_item) {
+                for enchantment in &mut piece.enchantments {
+                    enchantment.fits_item = enchantment.id != 2;
+                }
+            }
+            assert_solve_matches_brute_force(&config(false, case % 2 == 0), &pieces, case);
+        }
     }
 
-    let Some((best_cost, order)) = solve(&config, &pieces) else {
-        return String::from("No inputs, calculation not possible.\n");
-    };
-    let mut max_xp_cost = 0;
-    let mut total_level_cost = 0;
-    let mut separately_funded_xp_cost = 0;
-    let mut result = String::new();
-    for (index, record) in order.iter().enumerate() {
-        let left = &record.left;
-        let right = &record.right;
-        let (_, cost) = anvil(&config, left, right);
-        let (level_cost, xp_cost) = expand_cost(&config, cost);
-        total_level_cost += level_cost;
-        separately_funded_xp_cost += xp_cost;
-        max_xp_cost = cmp::max(max_xp_cost, xp_cost);
-        result += format!(
-            "{}. [{}: {},{}] + [{}: {},{}] = {} lvl ({} xp)\n",
-            index + 1,
-            get_name(&names, &left.name_indices),
-            left.value,
-            calc_penalty(left.work_count),
-            get_name(&names, &right.name_indices),
-            right.value,
-            calc_penalty(right.work_count),
-            level_cost,
-            xp_cost
+    #[test]
+    fn cost_overrides_match_brute_force() {
+        // Tome resets keep the names overrides refer to.
+        let config = |optimize_per_step, tome: bool| {
+            let mut config = Config {
+                tome: tome.then_some(Tome {
+                    cost: 1,
+                    material_cost: 0,
+                }),
+                cost_overrides: vec![
+                    CostOverride {
+                        target: Some(String::from("a")),
+                        sacrifice: String::from("b"),
+                        cost: 1,
+                    },
+                    CostOverride {
+                        target: None,
+                        sacrifice: String::from("c"),
+                        cost: 9,
+                    },
+                ],
+                ..config(false, optimize_per_step)
+            };
+            config.resolved_cost_overrides = resolve_cost_overrides(&config);
+            config
+        };
+        let mut random_state = 0x0_4E44;
+        for case in 0..24 {
+            let mut pieces = random_enchanted_pieces(&mut random_state);
+            for (index, piece) in pieces.iter_mut().take(3).enumerate() {
+                piece.override_names = 1 << index;
+                piece.work_count += WorkCount::from(piece.is_item) * 3;
+            }
+            let tome = case % 3 == 0;
+            if tome {
+                pieces.truncate(4);
+            }
+            assert_solve_matches_brute_force(&config(case % 2 == 0, tome), &pieces, case);
+        }
+
+        let schema = yaml_serde::from_str(
+            "config:
+  books_free: false
+  optimize_per_step: false
+  cost_overrides:
+    - { target: sword, sacrifice: upgrade kit, cost: 10 }
+input:
+  items:
+    - [sword, 0x0, 0]
+  books:
+    - [upgrade kit, 0x0, 3]
+",
         )
-        .as_str();
+        .unwrap();
+        assert!(
+            process(schema)
+                .unwrap()
+                .contains("[sword: 0,0] + [upgrade kit: 0,3] = 10 lvl")
+        );
     }
-    let objective = if config.optimize_per_step {
-        debug_assert_eq!(best_cost, separately_funded_xp_cost);
-        "exact levels for each step"
-    } else {
-        debug_assert_eq!(best_cost, total_level_cost);
-        "all levels up front"
-    };
-    let (displayed_total_levels, displayed_total_xp) = displayed_total_cost(
-        config.optimize_per_step,
-        total_level_cost,
-        separately_funded_xp_cost,
-    );
-    result += "\n";
-    result += format!("Optimized for: {objective}\n").as_str();
-    result += format!(
-        "Max step cost: {} lvl ({max_xp_cost} xp)\n",
-        calc_level(max_xp_cost)
-    )
-    .as_str();
-    result +=
-        format!("Total cost: {displayed_total_levels} lvl ({displayed_total_xp} xp)\n").as_str();
-    result
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Processes a sword and five books, with `options` as extra config lines.
+    fn process_sword(options: &str) -> String {
+        process(
+            yaml_serde::from_str(&format!(
+                "config:
+  books_free: false
+  optimize_per_step: false
+{options}
+input:
+  items:
+    - [sword, 0x0, 0]
+  books:
+    - [sharpness, 5x1, 0]
+    - [unbreaking, 3x1, 0]
+    - [looting, 3x2, 0]
+    - [mending, 1x2, 0]
+    - [fire, 2x2, 0]
+"
+            ))
+            .unwrap(),
+        )
+        .unwrap()
+    }
 
-    fn piece(index: usize, is_item: bool, value: Value, work_count: WorkCount) -> Piece {
-        Piece {
-            name_indices: vec![index],
-            is_item,
-            value,
-            work_count,
+    #[test]
+    fn min_max_step_matches_brute_force() {
+        let mut random_state = 0x3A_C5;
+        for case in 0..16 {
+            let mut pieces = random_enchanted_pieces(&mut random_state);
+            pieces.truncate(4);
+            let config = Config {
+                minimize_max_step: true,
+                ..config(false, case % 2 == 0)
+            };
+            let capped = |max_step_cost| Config {
+                max_step_cost: Some(max_step_cost),
+                ..config.clone()
+            };
+            let ((cost, trace), _) = solve_objectives(&config, &pieces).unwrap();
+            let lowest_cap = (0..100)
+                .find(|&cap| brute_force(&capped(cap), &pieces) != Cost::MAX)
+                .unwrap();
+            assert_eq!(
+                max_step_level_cost(&config, &trace),
+                lowest_cap,
+                "random case {case}"
+            );
+            assert_eq!(
+                cost,
+                brute_force(&capped(lowest_cap), &pieces),
+                "random case {case}"
+            );
         }
+
+        // The cheapest order ends on a 12 level step for 31 levels in total,
+        // while the lowest max step of 9 levels costs 35 levels in total.
+        let result = process_sword("");
+        assert!(result.contains("Max step cost: 12 lvl"));
+        assert!(result.contains("Total cost: 31 lvl"));
+        let result = process_sword("  minimize_max_step: true");
+        assert!(result.contains("Optimized for: lowest max step, then all levels up front"));
+        assert!(result.contains("Max step cost: 9 lvl"));
+        assert!(result.contains("Total cost: 35 lvl"));
     }
 
-    fn brute_force(config: &Config, pieces: &[Piece]) -> Cost {
+    /// Collects the step costs, in the order taken, and the final work count
+    /// of every order.
+    fn all_orders(
+        config: &Config,
+        pieces: &[Piece],
+        costs: &mut Vec<Cost>,
+        orders: &mut Vec<(Vec<Cost>, WorkCount)>,
+    ) {
         if pieces.len() == 1 {
-            return 0;
+            orders.push((costs.clone(), pieces[0].work_count));
+            return;
         }
-
-        let mut best = Cost::MAX;
         for first in 0..pieces.len() {
-            for second in first + 1..pieces.len() {
-                let mut left = pieces[first].clone();
-                let mut right = pieces[second].clone();
-                let same_type = left.is_item == right.is_item;
-                let swap = if same_type {
-                    left.value < right.value
-                } else {
-                    !left.is_item
-                };
-                if swap {
-                    mem::swap(&mut left, &mut right);
+            for second in 0..pieces.len() {
+                let (left, right) = (&pieces[first], &pieces[second]);
+                if first == second || (right.is_item && !left.is_item) {
+                    continue;
                 }
-
-                let (combined, merge_cost) = anvil(config, &left, &right);
-                let mut next = Vec::with_capacity(pieces.len() - 1);
-                next.extend(
-                    pieces
-                        .iter()
-                        .enumerate()
-                        .filter(|(index, _)| *index != first && *index != second)
-                        .map(|(_, piece)| piece.clone()),
-                );
+                let (combined, cost) = anvil(config, left, right, false);
+                let mut next = pieces
+                    .iter()
+                    .enumerate()
+                    .filter(|(index, _)| *index != first && *index != second)
+                    .map(|(_, piece)| piece.clone())
+                    .collect::<Vec<_>>();
                 next.push(combined);
-                best = cmp::min(best, merge_cost.saturating_add(brute_force(config, &next)));
+                costs.push(cost);
+                all_orders(config, &next, costs, orders);
+                costs.pop();
             }
         }
-        best
     }
 
-    fn next_random(state: &mut u64) -> u64 {
-        *state = state
-            .wrapping_mul(6_364_136_223_846_793_005)
-            .wrapping_add(1);
-        *state
+    /// The total, costliest step and final work count of every order.
+    fn all_outcomes(config: &Config, pieces: &[Piece]) -> Vec<(Cost, Cost, WorkCount)> {
+        let mut orders = Vec::new();
+        all_orders(config, pieces, &mut Vec::new(), &mut orders);
+        orders
+            .into_iter()
+            .map(|(costs, work_count)| {
+                let max_step = costs
+                    .iter()
+                    .map(|&cost| expand_cost(config, cost).0)
+                    .max()
+                    .unwrap_or(0);
+                (costs.iter().sum(), max_step, work_count)
+            })
+            .collect()
     }
 
     #[test]
-    fn grouped_dp_matches_brute_force() {
-        let mut random_state = 0xA11C_E5EED;
-        for case in 0..24 {
-            let piece_count = 2 + usize::try_from(next_random(&mut random_state) % 6).unwrap();
-            let mut pieces = Vec::with_capacity(piece_count);
-            for index in 0..piece_count {
-                let is_item = index == 0 || next_random(&mut random_state) % 5 == 0;
-                let piece_type = if is_item {
-                    PIECE_TYPE_ITEM
-                } else {
-                    PIECE_TYPE_BOOK
+    fn lexicographic_objectives_match_brute_force() {
+        use Objective::{FinalPenalty, MaxStep, Total};
+        let mut random_state = 0x1E_C5;
+        for case in 0..12 {
+            let mut pieces = random_enchanted_pieces(&mut random_state);
+            pieces.truncate(5);
+            let outcomes = all_outcomes(&config(false, false), &pieces);
+            for objectives in [
+                vec![Total, MaxStep, FinalPenalty],
+                vec![MaxStep, FinalPenalty],
+                vec![FinalPenalty, Total, MaxStep],
+                vec![FinalPenalty, MaxStep],
+            ] {
+                let config = Config {
+                    objectives: objectives.clone(),
+                    ..config(false, false)
                 };
-                pieces.push(piece(
-                    index,
-                    piece_type,
-                    Value::try_from(next_random(&mut random_state) % 13).unwrap(),
-                    WorkCount::try_from(next_random(&mut random_state) % 3).unwrap(),
-                ));
-            }
-
-            for books_free in [false, true] {
-                for optimize_per_step in [false, true] {
-                    let config = Config {
-                        books_free,
-                        optimize_per_step,
-                    };
-                    let expected = brute_force(&config, &pieces);
-                    let (actual, trace) = solve(&config, &pieces).unwrap();
-                    let trace_cost = trace
+                let ranked = |(total, max_step, work_count): (Cost, Cost, WorkCount)| {
+                    objectives
                         .iter()
-                        .map(|record| anvil(&config, &record.left, &record.right).1)
-                        .sum::<Cost>();
-                    assert_eq!(actual, expected, "random case {case}, {config:?}");
-                    assert_eq!(trace_cost, actual, "random case {case}, {config:?}");
-                    assert_eq!(trace.len(), piece_count - 1);
-                }
+                        .map(|objective| match objective {
+                            Total => total,
+                            MaxStep => max_step,
+                            FinalPenalty => config.penalty_curve.penalty(work_count),
+                        })
+                        .chain([total])
+                        .collect::<Vec<_>>()
+                };
+                let expected = outcomes.iter().copied().map(ranked).min().unwrap();
+                let ((cost, trace), _) = solve_objectives(&config, &pieces).unwrap();
+                let actual = ranked((
+                    cost,
+                    max_step_level_cost(&config, &trace),
+                    final_work_count(&config, &pieces, &trace),
+                ));
+                assert_eq!(actual, expected, "random case {case}, {objectives:?}");
             }
         }
+        let result = process_sword("  objectives: [final_penalty, max_step]");
+        assert!(result.contains(
+            "Optimized for: lowest final penalty, then lowest max step, then all levels up front"
+        ));
+        assert!(result.contains("Final penalty: 7"));
     }
 
     #[test]
-    fn grouped_dp_collapses_and_solves_the_21_piece_workload() {
-        let values = [
-            0, 12, 12, 4, 6, 4, 3, 2, 12, 8, 8, 4, 4, 4, 4, 6, 2, 5, 6, 4, 6,
-        ];
-        let pieces = values
-            .into_iter()
-            .enumerate()
-            .map(|(index, value)| piece(index, index == 0, value, 0))
-            .collect::<Vec<_>>();
-        let config = Config {
-            books_free: false,
-            optimize_per_step: true,
+    fn bisection_rounds_are_capped() {
+        // Every limit from 5 up has an order reaching exactly that limit.
+        let rounds = Cell::new(0);
+        let bisect = |start: Cost| {
+            rounds.set(0);
+            let limit = bisect_lowest_limit(
+                &mut (start, Box::default()),
+                |&(limit, _)| limit,
+                |limit| {
+                    rounds.set(rounds.get() + 1);
+                    (limit >= 5).then(|| (limit, Box::default()))
+                },
+            );
+            (limit, rounds.get())
         };
+        assert_eq!(bisect(1000), ((5, true), 10));
+        let ((limit, settled), rounds) = bisect(Cost::MAX);
+        assert_eq!(rounds, MAX_BISECTION_ROUNDS);
+        assert!(limit > 5 && !settled);
 
-        let (groups, state_count) = group_pieces(&pieces);
-        assert_eq!(groups.len(), 8);
-        assert_eq!(state_count, 11_520);
+        // A step of a billion levels is beyond what the rounds settle.
+        let schema = yaml_serde::from_str(
+            "config:
+  books_free: false
+  optimize_per_step: false
+  max_step_cost: ~
+  objectives: [max_step]
+input:
+  items:
+    - [sword, 0x0, 1073741823]
+  books:
+    - [sharpness, 5x1, 0]
+",
+        )
+        .unwrap();
+        assert!(process(schema).unwrap().contains(
+            "Optimized for: lowest max step (not settled after 16 search rounds), \
+             then all levels up front\n"
+        ));
+    }
 
-        let (cost, trace) = solve(&config, &pieces).unwrap();
-        assert_eq!(cost, 12_415);
-        assert_eq!(trace.len(), pieces.len() - 1);
-        let final_record = trace.last().unwrap();
-        let mut final_names = final_record.left.name_indices.clone();
-        final_names.extend_from_slice(&final_record.right.name_indices);
-        final_names.sort_unstable();
-        assert_eq!(final_names, (0..pieces.len()).collect::<Vec<_>>());
+    #[test]
+    fn pareto_frontier_matches_brute_force() {
+        let mut random_state = 0x9A_2E70;
+        for case in 0..12 {
+            let mut pieces = random_enchanted_pieces(&mut random_state);
+            pieces.truncate(5);
+            let config = config(false, case % 2 == 0);
+            let outcomes = all_outcomes(&config, &pieces);
+            let mut expected = outcomes
+                .iter()
+                .copied()
+                .filter(|&(total, step, work_count)| {
+                    !outcomes.iter().any(|&other| {
+                        other != (total, step, work_count)
+                            && other.0 <= total
+                            && other.1 <= step
+                            && other.2 <= work_count
+                    })
+                })
+                .collect::<Vec<_>>();
+            expected.sort_unstable();
+            expected.dedup();
+            let actual = solve_pareto_frontier(&config, &pieces)
+                .unwrap()
+                .iter()
+                .map(|(cost, trace)| {
+                    (
+                        *cost,
+                        max_step_level_cost(&config, trace),
+                        final_work_count(&config, &pieces, trace),
+                    )
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(actual, expected, "random case {case}");
+        }
+
+        let result = process_sword("  pareto_frontier: true");
+        assert!(result.contains("1   31 lvl (1507 xp)        10 lvl      7\n"));
+        assert!(result.contains("2   35 lvl (2045 xp)        9 lvl       15\n"));
+        assert!(result.contains("Plan 2:\n1. [sword: 0,0] + [looting: 6,0] = 6 lvl"));
     }
 
     #[test]
-    fn grouped_dp_supports_piece_counts_beyond_fixed_width_encodings() {
-        let pieces = (0..300)
-            .map(|index| piece(index, index == 0, u32::from(index != 0), 0))
-            .collect::<Vec<_>>();
-        let config = Config {
-            books_free: false,
-            optimize_per_step: false,
+    fn final_penalty_weight_and_reserve_match_brute_force() {
+        let mut random_state = 0xF0_7E;
+        for case in 0..12 {
+            let mut pieces = random_enchanted_pieces(&mut random_state);
+            pieces.truncate(5);
+            let outcomes = all_outcomes(&config(false, false), &pieces);
+            for (final_penalty_weight, reserve_operations) in [(1, 0), (4, 0), (0, 3), (2, 4)] {
+                let config = Config {
+                    final_penalty_weight,
+                    reserve_operations,
+                    max_step_cost: Some(20),
+                    reserve_max_step_cost: Some(20),
+                    ..config(false, false)
+                };
+                let expected = outcomes
+                    .iter()
+                    .filter(|&&(_, step, work_count)| {
+                        let last_work_count = u64::from(work_count) + u64::from(reserve_operations);
+                        step <= 20
+                            && (reserve_operations == 0 || calc_penalty(last_work_count - 1) < 20)
+                    })
+                    .map(|&(total, _, work_count)| {
+                        total + final_penalty_weight * calc_penalty(u64::from(work_count))
+                    })
+                    .min();
+                let actual = solve(&config, &pieces).ok().map(|(cost, _)| cost);
+                assert_eq!(actual, expected, "random case {case}, {config:?}");
+            }
+        }
+
+        // Five books take at least three operations on the sword, which then
+        // has room for three more before the penalty alone reaches 63 levels.
+        assert!(process_sword("  reserve_operations: 3").contains("Final penalty: 7"));
+        assert!(
+            process_sword("  reserve_operations: 4")
+                .starts_with("No feasible order: every order has a step above 39 lvl or leaves")
+        );
+    }
+
+    #[test]
+    fn objective_weights_are_bounded() {
+        let schema = |options: &str| -> Result<String, String> {
+            process(
+                yaml_serde::from_str(&format!(
+                    "config:
+  books_free: false
+  optimize_per_step: false
+{options}
+input:
+  items:
+    - [boots, 0x0, 0]
+  books:
+    - [protection, 4x1, 0]
+    - [feather falling, 4x1, 0]
+"
+                ))
+                .unwrap(),
+            )
         };
+        assert!(schema("  anvil_wear_cost: 2147483647").is_ok());
+        assert!(schema("  final_penalty_weight: 2147483647").is_ok());
+        assert_eq!(
+            schema("  anvil_wear_cost: 9223372036854775807"),
+            Err(String::from(
+                "anvil_wear_cost: at most 2147483647 is supported"
+            ))
+        );
+        assert_eq!(
+            schema("  final_penalty_weight: 18446744073709551615"),
+            Err(String::from(
+                "final_penalty_weight: at most 2147483647 is supported"
+            ))
+        );
+    }
 
-        let (groups, state_count) = group_pieces(&pieces);
-        assert_eq!(groups.len(), 2);
-        assert_eq!(state_count, 600);
+    #[test]
+    fn starting_level_prices_the_xp_drained() {
+        let mut config = config(false, false);
+        assert_eq!(
+            spend_levels(&config, 30, &[20, 20]),
+            (1_395 - 160 + 550, vec![(0, 30, 10), (10, 20, 0)])
+        );
+        config.level_floor = 5;
+        assert_eq!(
+            spend_levels(&config, 30, &[20, 20]),
+            (1_395 - 160 + 910 - 55, vec![(0, 30, 10), (15, 25, 5)])
+        );
 
-        let (_, trace) = solve(&config, &pieces).unwrap();
-        assert_eq!(trace.len(), pieces.len() - 1);
-        let final_record = trace.last().unwrap();
-        let mut final_names = final_record.left.name_indices.clone();
-        final_names.extend_from_slice(&final_record.right.name_indices);
-        final_names.sort_unstable();
-        assert_eq!(final_names, (0..pieces.len()).collect::<Vec<_>>());
+        // Spending only what the player holds, fewer levels drain less XP.
+        let result = process_sword("  starting_level: 100");
+        assert!(result.contains("Optimized for: all levels up front\n"));
+        assert!(result.contains("1. level 100 -> 94\n"));
+        assert!(result.contains("XP drained: 18538 xp"));
+        // From nothing, every step is earned right before it.
+        let result = process_sword("  starting_level: 0");
+        assert!(result.contains("Optimized for: all levels up front\n"));
+        assert!(result.contains("5. level 12 -> 0 (earn 12 lvl first)\n"));
+        assert!(result.contains("XP drained: 443 xp"));
+
+        let result = process(
+            yaml_serde::from_str(
+                "config:
+  books_free: false
+  optimize_per_step: false
+  level_floor: 5
+input:
+  items:
+    - [sword, 0x0, 0]
+  books:
+    - [sharpness, 5x1, 0]
+",
+            )
+            .unwrap(),
+        );
+        assert_eq!(
+            result,
+            Err(String::from(
+                "level_floor: only applies from a starting_level"
+            ))
+        );
     }
 
     #[test]
-    fn public_api_accepts_more_than_21_pieces() {
-        let schema = ConfigSchema {
-            config: Config {
-                books_free: false,
-                optimize_per_step: false,
-            },
-            input: Input {
-                items: vec![(String::from("item"), String::from("0x0"), 0)],
-                books: (0..21)
-                    .map(|index| (format!("book {index}"), String::from("1x1"), 0))
-                    .collect(),
-            },
+    fn starting_level_picks_the_order_draining_the_least_xp() {
+        let schema = |starting_level: &str| {
+            process(
+                yaml_serde::from_str(&format!(
+                    "config:
+  books_free: false
+  optimize_per_step: true
+  max_step_cost: ~
+{starting_level}
+input:
+  items:
+    - [sword, 0x0, 3]
+  books:
+    - [b0, 5x1, 0]
+    - [b1, 5x2, 0]
+"
+                ))
+                .unwrap(),
+            )
+            .unwrap()
         };
+        // Two smaller steps take the fewest XP points when earned for each.
+        let result = schema("");
+        assert!(result.contains(
+            "1. [sword: 0,3] + [b1: 10,0] = 13 lvl (247 xp)\n\
+             2. [sword + b1: 10,7] + [b0: 5,0] = 12 lvl (216 xp)\n"
+        ));
+        // From level 30 that order drains 1395 - 55 = 1340 xp, while one fewer
+        // level in total drains less.
+        let result = schema("  starting_level: 30");
+        assert!(result.contains(
+            "1. [b1: 10,0] + [b0: 5,0] = 5 lvl (55 xp)\n\
+             2. [sword: 0,3] + [b1 + b0: 15,1] = 19 lvl (493 xp)\n"
+        ));
+        assert!(result.contains("XP drained: 1323 xp\n"));
+    }
 
-        let result = process(schema);
-        assert!(result.contains("Total cost:"));
-        assert!(result.contains("book 20"));
+    #[test]
+    fn spent_levels_match_level_by_level_replay() {
+        let mut random_state = 0x5_7A27;
+        for case in 0..12 {
+            let mut pieces = random_enchanted_pieces(&mut random_state);
+            pieces.truncate(5);
+            let config = config(false, false);
+            let mut orders = Vec::new();
+            all_orders(&config, &pieces, &mut Vec::new(), &mut orders);
+            let orders = orders
+                .into_iter()
+                .map(|(costs, _)| {
+                    costs
+                        .into_iter()
+                        .map(|cost| expand_cost(&config, cost).0)
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            // Start partway through what the orders spend, so the player runs
+            // out of levels midway.
+            let starting_level = orders
+                .iter()
+                .map(|order| order.iter().sum::<Cost>())
+                .min()
+                .unwrap()
+                / 2;
+            for level_floor in [0, 1, 3] {
+                let config = Config {
+                    level_floor,
+                    ..config.clone()
+                };
+                let xp = |level| config.xp_curve.xp(level);
+                for order in &orders {
+                    // Earn and spend one level at a time.
+                    let mut level = starting_level;
+                    let mut drained = 0;
+                    let mut steps = Vec::new();
+                    for &level_cost in order {
+                        let mut earned = 0;
+                        while level < level_floor + level_cost {
+                            level += 1;
+                            earned += 1;
+                        }
+                        let before = level;
+                        for _ in 0..level_cost {
+                            drained += xp(level) - xp(level - 1);
+                            level -= 1;
+                        }
+                        steps.push((earned, before, level));
+                    }
+                    assert_eq!(
+                        spend_levels(&config, starting_level, order),
+                        (drained, steps),
+                        "random case {case}"
+                    );
+                }
+            }
+        }
     }
 
     #[test]
-    fn displayed_total_cost_matches_the_optimization_mode() {
-        assert_eq!(displayed_total_cost(false, 66, 1_246), (66, 11_097));
-        assert_eq!(displayed_total_cost(true, 66, 1_246), (29, 1_246));
+    fn reserve_survives_lowered_caps() {
+        let mut random_state = 0x2E_5E2E;
+        for case in 0..12 {
+            let mut pieces = random_enchanted_pieces(&mut random_state);
+            pieces.truncate(5);
+            let outcomes = all_outcomes(&config(false, false), &pieces);
+            for reserve_operations in [2, 3] {
+                let config = Config {
+                    reserve_operations,
+                    max_step_cost: Some(20),
+                    reserve_max_step_cost: Some(20),
+                    ..config(false, false)
+                };
+                let allowed = outcomes
+                    .iter()
+                    .copied()
+                    .filter(|&(_, step, work_count)| {
+                        let last_work_count = u64::from(work_count + reserve_operations - 1);
+                        step <= 20 && calc_penalty(last_work_count) < 20
+                    })
+                    .collect::<Vec<_>>();
+
+                let expected = allowed.iter().map(|&(total, step, _)| (step, total)).min();
+                let minimax = Config {
+                    minimize_max_step: true,
+                    ..config.clone()
+                };
+                let actual = solve_objectives(&minimax, &pieces)
+                    .ok()
+                    .map(|((cost, trace), _)| (max_step_level_cost(&minimax, &trace), cost));
+                assert_eq!(actual, expected, "random case {case}, {reserve_operations}");
+
+                let mut expected = allowed
+                    .iter()
+                    .copied()
+                    .filter(|&outcome| {
+                        !allowed.iter().any(|&other| {
+                            other != outcome
+                                && other.0 <= outcome.0
+                                && other.1 <= outcome.1
+                                && other.2 <= outcome.2
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                expected.sort_unstable();
+                expected.dedup();
+                let actual = solve_pareto_frontier(&config, &pieces)
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|(cost, trace)| {
+                        (
+                            *cost,
+                            max_step_level_cost(&config, trace),
+                            final_work_count(&config, &pieces, trace),
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                assert_eq!(actual, expected, "random case {case}, {reserve_operations}");
+            }
+        }
     }
 
     #[test]
-    fn xp_point_optimization_can_choose_a_different_order() {
-        let pieces = [
-            piece(0, PIECE_TYPE_ITEM, 0, 0),
-            piece(1, PIECE_TYPE_BOOK, 1, 0),
-            piece(2, PIECE_TYPE_BOOK, 4, 0),
-            piece(3, PIECE_TYPE_BOOK, 4, 0),
-        ];
-        let levels_config = Config {
-            books_free: false,
-            optimize_per_step: false,
-        };
-        let xp_points_config = Config {
-            books_free: false,
-            optimize_per_step: true,
-        };
+    fn tomes_match_brute_force() {
+        let mut random_state = 0x70_4E5;
+        for case in 0..16 {
+            let mut pieces = random_enchanted_pieces(&mut random_state);
+            pieces.truncate(4);
+            pieces[0].rename = case % 3 == 0;
+            for piece in pieces.iter_mut().filter(|piece| piece.is_item) {
+                piece.work_count += 3;
+                // Resets keep the item's damage rather than repairing it.
+                if case % 4 < 2 {
+                    piece.durability = Some(Durability {
+                        damage: Value::try_from(next_random(&mut random_state) % 100).unwrap(),
+                        max: 100,
+                        material: case % 4 == 0,
+                    });
+                }
+            }
+            let config = Config {
+                tome: Some(Tome {
+                    cost: 3,
+                    material_cost: 2,
+                }),
+                ..config(false, case % 2 == 0)
+            };
+            assert_solve_matches_brute_force(&config, &pieces, case);
+        }
+    }
 
-        let (level_objective, level_trace) = solve(&levels_config, &pieces).unwrap();
-        let (xp_objective, xp_trace) = solve(&xp_points_config, &pieces).unwrap();
-        let trace_costs = |trace: &[TraceRecord]| {
-            trace
-                .iter()
-                .map(|record| anvil(&levels_config, &record.left, &record.right).1)
-                .fold((0, 0), |(levels, xp_points), level_cost| {
-                    (levels + level_cost, xp_points + calc_xp(level_cost))
-                })
+    #[test]
+    fn tomes_reset_prior_work() {
+        let schema = |tome: &str| {
+            process(
+                yaml_serde::from_str(&format!(
+                    "config:
+  books_free: false
+  optimize_per_step: false
+{tome}
+  enchantments: {{ sharpness: {{ max_level: 5 }} }}
+input:
+  items:
+    - {{ name: sword, penalty: 31, enchantments: {{ sharpness: 5x1 }} }}
+  books:
+    - [unbreaking, 3x1, 0]
+"
+                ))
+                .unwrap(),
+            )
+            .unwrap()
         };
+        assert!(schema("").contains("Total cost: 34 lvl"));
+        let result = schema("  tome: { cost: 5 }");
+        assert!(result.contains(
+            "1. [sword: 5,31] + tome = 5 lvl (55 xp)\n\
+             2. [sword: 0,0] + [tome book: 5,0] = 5 lvl (55 xp)\n\
+             3. [sword: 5,1] + [unbreaking: 3,0] = 4 lvl (40 xp)\n"
+        ));
+        assert!(result.contains("Total cost: 14 lvl"));
+    }
 
-        assert_eq!(trace_costs(&level_trace), (12, 138));
-        assert_eq!(trace_costs(&xp_trace), (13, 135));
-        assert_eq!(level_objective, 12);
-        assert_eq!(xp_objective, 135);
+    #[test]
+    fn tome_resets_of_equally_cheap_entries_survive_pruning() {
+        // The item's prior work ties with that of its merges, so resets of
+        // several entries cost the same.
+        let pieces = [
+            piece(0, PIECE_TYPE_ITEM, 1, 2),
+            piece(1, PIECE_TYPE_BOOK, 4, 1),
+            piece(2, PIECE_TYPE_BOOK, 0, 1),
+            piece(3, PIECE_TYPE_BOOK, 0, 0),
+        ];
+        let config = Config {
+            max_step_cost: None,
+            tome: Some(Tome {
+                cost: 1,
+                material_cost: 0,
+            }),
+            ..config(false, false)
+        };
+        // Three resets take the cheapest order from 19 levels to 15.
+        assert_eq!(brute_force(&config, &pieces), 15);
+        assert_solve_matches_brute_force(&config, &pieces, 0);
+        let (_, trace) = solve(&config, &pieces).unwrap();
+        assert_eq!(trace.iter().filter(|record| record.tome).count(), 3);
     }
 }
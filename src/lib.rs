@@ -22,7 +22,7 @@ pub fn process_wasm(input: &str) -> Result<String, JsError> {
     let start = performance_now();
     let config: ConfigSchema = yaml_serde::from_str(input)
         .map_err(|error| JsError::new(&format!("unable to parse input: {error}")))?;
-    let result = process(config);
+    let result = process(config).map_err(|error| JsError::new(&error))?;
     log(&format!("Done in {:.0}ms", performance_now() - start));
     Ok(result)
 }
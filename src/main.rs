@@ -10,7 +10,10 @@ fn main() {
     let start = std::time::Instant::now();
     let file = File::open("config.yml").expect("unable to open config.yml");
     let config: ConfigSchema = yaml_serde::from_reader(file).expect("unable to read config.yml");
-    let result = process(config);
+    let result = process(config).unwrap_or_else(|error| {
+        eprintln!("Invalid config.yml: {error}");
+        std::process::exit(1);
+    });
     println!("Done in {}ms", start.elapsed().as_millis());
     println!("{result}");
 }